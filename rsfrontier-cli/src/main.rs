@@ -1,23 +1,37 @@
-use core::panic;
 use std::{
+    error::Error,
     fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process,
     time::Instant,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use rsfrontier_core::{
-    FolderPackType, PackType,
-    ecd::{decrypt_ecd, is_buf_ecd},
-    pack_buffer, pack_em_folder, pack_folder, unpack_buffer,
+    FolderPackType, JpkSelection, PackOptions, PackType, UnpackOptions,
+    diff::{Change, Difference, NodeKind, diff_buffers},
+    ecd::{EcdParams, decrypt_ecd_unchecked, is_buf_ecd},
+    entry::{StopAt, extract_entry, replace_entry},
+    exf::{ExfParams, decrypt_exf_with_header, is_buf_exf},
+    ftxt::{Ftxt, decode_ftxt, encode_ftxt, is_buf_ftxt},
+    inspect::{InspectContent, InspectNode, LayerInfo, inspect_buffer},
+    jpk::CompressionLevel,
+    manifest::{Layer, Manifest, manifest_path},
+    pack_buffer, pack_buffer_with_options, pack_em_folder_with_options, pack_folder_with_options,
+    read_mha_metadata, repack_from_manifest_with_options,
+    simple_archive::DEFAULT_CONFIDENCE_THRESHOLD,
+    string_table::{ScanOptions, reinsert_strings, scan_strings},
+    text::{TableFormat, read_table, write_table},
+    unpack_buffer_with_options,
+    verify::{VerifyStatus, verify_path},
 };
 
 /// A command-line tool for packing and unpacking various file formats
 /// used in Monster Hunter Frontier Z (MHFZ).
 ///
 /// Supports automatic handling of:
-/// - ECD Encryption/Decryption
+/// - ECD and EXF Encryption/Decryption
 /// - JPK Compression/Decompression (Types 0, 2, 3, 4)
 /// - Simple Archive Packing/Unpacking
 /// - MHA/ABN Archive Packing/Unpacking
@@ -36,13 +50,21 @@ enum Commands {
     /// - By default, it creates a 'Simple Archive'.
     /// - Subdirectories become nested Simple Archives.
     /// - Files matching known extensions (.bin, .fmod, .fskl) inside directories
-    ///   are automatically compressed using JPK Type 4 (Huffman+LZ) before archiving.
-    /// - Use the --mha flag to create an MHA archive instead (requires --capacity and --baseid).
+    ///   are automatically compressed using JPK Type 3 (LZ), or the type given to --compression,
+    ///   before archiving.
+    /// - Directories written by `unpack` hold a '.pack.json' marker instead, listing the
+    ///   compression/encryption of the directory and of each file in it, which is re-applied
+    ///   as recorded. Nested archives are rebuilt as they were.
+    /// - Use the --mha flag to create an MHA archive instead (requires --capacity and
+    ///   --baseid unless the directory has a '.metadata' file).
+    /// - Directories unpacked from an MHA archive are packed back as MHA archives, using the
+    ///   header values and entry details from their '.metadata' file. This also applies to
+    ///   nested subdirectories. --capacity and --baseid override the values of the input directory.
     ///
     /// When packing a single file:
     /// - Use --compression to apply JPK compression.
     ///
-    /// Use --encrypt to apply ECD encryption to the final output (after any packing/compression).
+    /// Use --encrypt to apply ECD (or EXF) encryption to the final output (after any packing/compression).
     Pack {
         /// Path to the input file or directory to pack.
         #[arg(short, long, value_name = "PATH")]
@@ -53,47 +75,77 @@ enum Commands {
         #[arg(short, long, value_name = "FILE")]
         output: Option<PathBuf>,
 
-        /// JPK compression type to apply (only when the input is a single file).
+        /// JPK compression type to apply.
         /// Valid types:
         ///   0: Raw (no compression)
         ///   2: HuffmanRW
         ///   3: LZ
         ///   4: Huffman + LZ
-        /// If input is a directory, JPK compression is applied automatically where appropriate.
-        #[arg(short, long)]
-        compression: Option<u8>,
+        ///   auto: Try every type (see --jpk-types) and keep the smallest result
+        /// If input is a directory, this is the type given to files compressed because of their
+        /// extension (3 by default). Directories with a '.pack.json' keep their recorded types.
+        #[arg(short, long, value_name = "TYPE", value_parser = parse_compression)]
+        compression: Option<Compression>,
 
-        /// Encrypt the final output buffer using ECD encryption.
+        /// JPK types tried by '--compression auto', e.g. '3,4'. Defaults to 0,2,3,4.
+        #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+        jpk_types: Vec<u16>,
+
+        /// How hard JPK types 3 and 4 search for repeated data, for the file given to
+        /// --compression and for everything compressed inside a directory.
+        #[arg(long, alias = "level", value_enum, default_value_t = Level::Default)]
+        compression_level: Level,
+
+        /// Encrypt the final output buffer, using ECD unless 'exf' is given.
         /// This happens *after* all packing and compression steps.
-        #[arg(short, long)]
-        encrypt: bool,
+        #[arg(short, long, value_name = "SCHEME", num_args = 0..=1, default_missing_value = "ecd")]
+        encrypt: Option<Encryption>,
+
+        /// Key index to encrypt with (0 to 5). Defaults to 4.
+        /// Use the index reported by `unpack --decrypt` to re-encrypt a file with its original header.
+        #[arg(long, alias = "ecd-index", value_name = "INDEX", requires = "encrypt")]
+        key_index: Option<u16>,
+
+        /// Version field written in the encryption header. Defaults to 31739.
+        #[arg(
+            long,
+            alias = "ecd-version",
+            value_name = "VERSION",
+            requires = "encrypt"
+        )]
+        key_version: Option<u16>,
 
         /// Pack the input directory as an MHA archive instead of a Simple Archive.
         /// This flag is only effective when the input path is a directory.
-        /// Requires --capacity and --baseid to be specified.
+        /// Requires --capacity and --baseid unless the directory has a '.metadata' file.
         #[arg(long)]
         mha: bool,
 
         /// Set the 'capacity' field for the MHA archive header.
-        /// Required if --mha is used.
-        #[arg(long, value_name = "COUNT", requires = "mha")]
+        /// Required if --mha is used on a directory without a '.metadata' file.
+        #[arg(long, value_name = "COUNT")]
         capacity: Option<u16>,
 
         /// Set the 'base file ID' for the MHA archive header.
         /// Files within the MHA will be assigned IDs starting from this value.
-        /// Required if --mha is used.
-        #[arg(long, value_name = "ID", requires = "mha")]
+        /// Required if --mha is used on a directory without a '.metadata' file.
+        #[arg(long, value_name = "ID")]
         baseid: Option<u16>,
 
         /// Flag for specially handling monster archives that need a special packing scheme.
         #[arg(long)]
         em: bool,
+
+        /// Number of threads files inside the directory are packed on.
+        /// Defaults to one per CPU core.
+        #[arg(short, long, value_name = "COUNT")]
+        jobs: Option<usize>,
     },
 
     /// Unpacks an MHFZ file recursively, handling nested archives and compressions.
     ///
     /// Automatically detects and handles:
-    /// - ECD encryption
+    /// - ECD and EXF encryption
     /// - JPK compression (Types 0, 2, 3, 4)
     /// - Simple Archives
     /// - MHA Archives
@@ -117,70 +169,514 @@ enum Commands {
         #[arg(short, long, value_name = "DIR")]
         output: Option<PathBuf>,
 
-        /// Only decrypt the input file using ECD without unpacking.
+        /// Only decrypt the input file (ECD or EXF) without unpacking.
         /// This is useful for decrypting files without further processing.
         #[arg(long)]
         decrypt: bool,
+
+        /// Also write a '<output>.rsfrontier.json' manifest next to the output, recording
+        /// every layer (ECD/EXF, JPK, Simple/MHA archive) so the file can be rebuilt exactly.
+        #[arg(long)]
+        manifest: bool,
+
+        /// Don't stop on ECD files whose decrypted data doesn't match the CRC32 in their
+        /// header. A warning is printed and the (possibly corrupted) data is unpacked anyway.
+        #[arg(long)]
+        lenient: bool,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+
+        /// Number of threads archive entries are unpacked on. Defaults to one per CPU core.
+        /// The output is the same whatever the count.
+        #[arg(short, long, value_name = "COUNT")]
+        jobs: Option<usize>,
+    },
+
+    /// Prints the layers and archive entries of a file as a tree, without extracting anything.
+    ///
+    /// Uses the same detection as `unpack`. Every node lists its size, its ECD/EXF
+    /// encryption (key index, version, CRC32), JPK compression (type, sizes, ratio) and
+    /// what is left once they are removed: a Simple Archive (entry offsets), an MHA archive
+    /// (entry names and IDs) or a file with the extension `unpack` would give it.
+    Inspect {
+        /// Path to the file to inspect.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Print the tree as JSON instead.
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+    },
+
+    /// Compares two files node by node and lists what changed.
+    ///
+    /// Both files are walked with the same detection as `unpack`. Simple Archive entries
+    /// are paired by index and MHA entries by name. Reports added and removed entries,
+    /// changed ECD/EXF keys and JPK types, MHA IDs and entry order, archives re-encoded
+    /// with another layout, and for changed files their sizes and the offset of the first
+    /// differing byte. Paths can be given to `extract --entry`.
+    Diff {
+        /// The original file.
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+
+        /// The modified file.
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+
+        /// Print the differences as JSON instead.
+        #[arg(long)]
+        json: bool,
+
+        /// Don't stop on ECD files whose decrypted data doesn't match their CRC32.
+        #[arg(long)]
+        lenient: bool,
+
+        /// Number of threads entries are compared on. Defaults to one per CPU core.
+        #[arg(short, long, value_name = "COUNT")]
+        jobs: Option<usize>,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+    },
+
+    /// Writes a single nested entry of a file, without unpacking the rest.
+    ///
+    /// The entry is given as a path through the nested archives, as laid out by `unpack`:
+    /// Simple Archive entries by index, MHA entries by index or name. ECD/EXF and JPK
+    /// layers along the way are removed, the entry itself is fully unpacked unless
+    /// --stop-at is given.
+    Extract {
+        /// Path to the archive.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Path of the entry inside the archive, e.g. '0003/0012'.
+        #[arg(short, long, value_name = "PATH")]
+        entry: String,
+
+        /// Path to the output file.
+        /// If omitted, the entry is written to standard output (stdout).
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Keep the entry's layers from the first one of this kind:
+        ///   stored: the entry as stored in its archive
+        ///   ecd, exf: still encrypted
+        ///   jpk: decrypted but still compressed
+        #[arg(long, value_enum, value_name = "LAYER")]
+        stop_at: Option<StopLayer>,
+
+        /// Don't stop on ECD files whose decrypted data doesn't match their CRC32.
+        #[arg(long)]
+        lenient: bool,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+    },
+
+    /// Replaces a single nested entry of a file and rebuilds the layers around it.
+    ///
+    /// Only the archives and layers leading to the entry are decoded. Each of them is
+    /// encoded again as it was (ECD/EXF key, JPK type, Simple Archive layout, MHA IDs),
+    /// so the other entries are left untouched. The entry's own compression and encryption
+    /// are applied to the new file, unless --stop-at says it already has them.
+    Replace {
+        /// Path to the archive.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Path of the entry inside the archive, e.g. '0003/0012'.
+        #[arg(short, long, value_name = "PATH")]
+        entry: String,
+
+        /// Path to the new file.
+        #[arg(short, long = "with", value_name = "FILE")]
+        with: PathBuf,
+
+        /// Path to the output file.
+        /// If omitted, the rebuilt archive is written to standard output (stdout).
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// The new file still has the entry's layers from the first one of this kind,
+        /// same values as for `extract`.
+        #[arg(long, value_enum, value_name = "LAYER")]
+        stop_at: Option<StopLayer>,
+
+        /// How hard recompressed JPK layers search for repeated data.
+        #[arg(long, alias = "level", value_enum, default_value_t = Level::Default)]
+        compression_level: Level,
+
+        /// Don't stop on ECD files whose decrypted data doesn't match their CRC32.
+        #[arg(long)]
+        lenient: bool,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+    },
+
+    /// Rebuilds a file from the manifest written by `unpack --manifest`.
+    ///
+    /// Every layer recorded at unpack time (ECD/EXF, JPK type, Simple/MHA archive nesting,
+    /// entry order and alignment) is re-applied as is. Files edited in the unpacked
+    /// folder are picked up in place of the original data.
+    Repack {
+        /// Path to the '.rsfrontier.json' manifest produced by `unpack --manifest`.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Path to the output file.
+        /// If omitted, the packed data is written to standard output (stdout).
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// How hard recompressed JPK layers search for repeated data.
+        #[arg(long, alias = "level", value_enum, default_value_t = Level::Default)]
+        compression_level: Level,
+
+        /// Number of threads archive entries are rebuilt on. Defaults to one per CPU core.
+        #[arg(short, long, value_name = "COUNT")]
+        jobs: Option<usize>,
     },
+
+    /// Checks that files can be unpacked and rebuilt without changes.
+    ///
+    /// Every file of the input (a single file or a whole directory tree) is unpacked like
+    /// `unpack` does, checking ECD CRC32s, then each node is rebuilt with the parameters it
+    /// was read with and compared to the original. Whole files are also unpacked to a
+    /// temporary folder and rebuilt from there like `repack` and `pack` would. Nodes are
+    /// identical, have the same data once unpacked (JPK layers compressed differently), or
+    /// differ. Fails if any node differs or any file can't be unpacked.
+    Verify {
+        /// Path to the file or directory to check.
+        #[arg(short, long, value_name = "PATH")]
+        input: PathBuf,
+
+        /// Print the result of every node as JSON instead.
+        #[arg(long)]
+        json: bool,
+
+        /// Also fail when nodes only have the same data.
+        #[arg(long)]
+        strict: bool,
+
+        /// How hard rebuilt JPK layers search for repeated data.
+        #[arg(long, alias = "level", value_enum, default_value_t = Level::Default)]
+        compression_level: Level,
+
+        /// Don't stop on ECD files whose decrypted data doesn't match their CRC32.
+        #[arg(long)]
+        lenient: bool,
+
+        /// Number of threads files and entries are checked on. Defaults to one per CPU core.
+        #[arg(short, long, value_name = "COUNT")]
+        jobs: Option<usize>,
+
+        #[command(flatten)]
+        detection: DetectionArgs,
+    },
+
+    /// Exports the strings of text and data files to CSV/TSV for translation, and imports them back.
+    Text {
+        #[command(subcommand)]
+        command: TextCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum TextCommands {
+    /// Writes every string of a file to a table with 'id' and 'text' columns.
+    ///
+    /// For '.ftxt' files the id of a string is its index in the file. Other files, like a
+    /// decompressed mhfdat/mhfpac/mhfinf, are scanned for pointers to Shift-JIS strings and
+    /// the id is the string's offset. Keep ids as they are when editing.
+    Export {
+        /// Path to the '.ftxt' or decompressed data file, as produced by `unpack`.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Path to the table to write.
+        /// If omitted, the table is written to standard output (stdout).
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Table format. Defaults to the output's extension, or CSV.
+        #[arg(long)]
+        format: Option<TextFormat>,
+
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+
+    /// Rebuilds an '.ftxt' or data file from an edited table.
+    ///
+    /// Columns other than 'id' and 'text' are ignored. In data files, edited strings are
+    /// appended to the end of the file and every pointer to them is updated, the result
+    /// can be compressed and encrypted again with `pack`.
+    Import {
+        /// Path to the edited CSV/TSV table.
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        /// Path to the file to write.
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// The file the table was exported from, required for data files. For '.ftxt'
+        /// files its header is kept and strings missing from the table keep their original
+        /// text, without it the table must list every string.
+        #[arg(long, value_name = "FILE")]
+        original: Option<PathBuf>,
+
+        /// Table format. Defaults to the input's extension, or CSV.
+        #[arg(long)]
+        format: Option<TextFormat>,
+
+        /// Must match the options given to `text export`.
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+}
+
+/// Decides which data is split as a Simple Archive, which has no magic number.
+#[derive(Args)]
+struct DetectionArgs {
+    /// How sure (0 to 1) the detector must be before splitting data as a Simple Archive.
+    /// Raise it if plain files are unpacked as archives, lower it if archives are missed.
+    #[arg(long, value_name = "SCORE", default_value_t = DEFAULT_CONFIDENCE_THRESHOLD)]
+    archive_threshold: f32,
+
+    /// Always unpack this node as a Simple Archive, whatever its score.
+    /// Paths are relative to the output's parent, e.g. 'mhfdat/0003'. Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    force_archive: Vec<String>,
+
+    /// Never unpack this node as a Simple Archive, e.g. 'mhfdat/0003'. Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    not_archive: Vec<String>,
+}
+
+impl From<DetectionArgs> for UnpackOptions {
+    fn from(value: DetectionArgs) -> Self {
+        let simple_archive_overrides = value
+            .force_archive
+            .into_iter()
+            .map(|path| (path, true))
+            .chain(value.not_archive.into_iter().map(|path| (path, false)))
+            .collect();
+        UnpackOptions {
+            simple_archive_threshold: value.archive_threshold,
+            simple_archive_overrides,
+            ..Default::default()
+        }
+    }
+}
+
+/// Narrows down the pointer scan of data files, ignored for '.ftxt' files.
+#[derive(Args)]
+struct ScanArgs {
+    /// Offset where the pointer tables start (decimal or 0x prefixed hex).
+    #[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+    pointer_start: Option<usize>,
+
+    /// Offset where the pointer tables end (decimal or 0x prefixed hex).
+    #[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+    pointer_end: Option<usize>,
+
+    /// Strings shorter than this many bytes are ignored. Defaults to 2.
+    #[arg(long, value_name = "BYTES")]
+    min_length: Option<usize>,
+}
+
+impl From<ScanArgs> for ScanOptions {
+    fn from(value: ScanArgs) -> Self {
+        let default_options = ScanOptions::default();
+        let pointer_range = match (value.pointer_start, value.pointer_end) {
+            (None, None) => None,
+            (start, end) => Some(start.unwrap_or(0)..end.unwrap_or(usize::MAX)),
+        };
+        ScanOptions {
+            pointer_range,
+            min_length: value.min_length.unwrap_or(default_options.min_length),
+            ..default_options
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Compression {
+    Type(u16),
+    Auto,
+}
+
+fn parse_compression(value: &str) -> Result<Compression, String> {
+    match value {
+        "auto" => Ok(Compression::Auto),
+        "0" | "2" | "3" | "4" => Ok(Compression::Type(value.parse().unwrap())),
+        _ => Err("valid types are 0, 2, 3, 4 and auto".to_string()),
+    }
+}
+
+fn parse_offset(value: &str) -> Result<usize, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| e.to_string())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TextFormat {
+    Csv,
+    Tsv,
+}
+
+impl From<TextFormat> for TableFormat {
+    fn from(value: TextFormat) -> Self {
+        match value {
+            TextFormat::Csv => TableFormat::Csv,
+            TextFormat::Tsv => TableFormat::Tsv,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Level {
+    Fast,
+    Default,
+    Max,
+    Optimal,
+}
+
+impl From<Level> for CompressionLevel {
+    fn from(value: Level) -> Self {
+        match value {
+            Level::Fast => CompressionLevel::Fast,
+            Level::Default => CompressionLevel::Default,
+            Level::Max => CompressionLevel::Max,
+            Level::Optimal => CompressionLevel::Optimal,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StopLayer {
+    Stored,
+    Ecd,
+    Exf,
+    Jpk,
+}
+
+impl From<StopLayer> for StopAt {
+    fn from(value: StopLayer) -> Self {
+        match value {
+            StopLayer::Stored => StopAt::Stored,
+            StopLayer::Ecd => StopAt::Ecd,
+            StopLayer::Exf => StopAt::Exf,
+            StopLayer::Jpk => StopAt::Jpk,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Encryption {
+    Ecd,
+    Exf,
 }
 
 fn main() {
     let cli = Cli::parse();
     let start = Instant::now();
 
+    if let Err(err) = run(cli) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+
+    let duration = start.elapsed();
+    // stderr, so the timing doesn't end up in data written to stdout
+    eprintln!("Processed command in {:?}", duration);
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
     match cli.command {
         Commands::Pack {
             input,
             output,
             compression,
+            jpk_types,
+            compression_level,
             encrypt,
+            key_index,
+            key_version,
             mha,
             capacity,
             baseid,
             em,
+            jobs,
         } => {
             let packed_data;
+            let jpk_selection = compression.map(|compression| match compression {
+                Compression::Type(jpk_type) => JpkSelection::Type(jpk_type),
+                Compression::Auto => JpkSelection::Auto(jpk_types),
+            });
+            let pack_options = PackOptions {
+                compression: compression_level.into(),
+                jpk_type: jpk_selection.clone().unwrap_or_default(),
+                jobs,
+            };
 
             if input.is_dir() {
-                if mha {
+                let metadata = read_mha_metadata(&input)?;
+                if mha || (metadata.is_some() && !em) {
                     if em {
-                        panic!("--em cannot be used with --mha. Use --mha only for MHA archives.");
+                        return Err(
+                            "--em cannot be used with --mha. Use --mha only for MHA archives."
+                                .into(),
+                        );
                     }
-                    let capacity = capacity.expect("--capacity is required with --mha");
-                    let baseid = baseid.expect("--baseid is required with --mha");
-                    packed_data = pack_folder(&input, FolderPackType::MHA(baseid, capacity));
+                    let capacity = capacity.or(metadata.as_ref().map(|m| m.capacity)).ok_or(
+                        "--capacity is required with --mha when there is no .metadata file",
+                    )?;
+                    let baseid = baseid
+                        .or(metadata.as_ref().map(|m| m.base_id))
+                        .ok_or("--baseid is required with --mha when there is no .metadata file")?;
+                    packed_data = pack_folder_with_options(
+                        &input,
+                        FolderPackType::MHA(baseid, capacity),
+                        &pack_options,
+                    )?;
                 } else if em {
-                    packed_data = pack_em_folder(&input);
+                    packed_data = pack_em_folder_with_options(&input, &pack_options)?;
                 } else {
-                    if compression.is_some() {
-                        panic!(
-                            "--compression cannot be used when packing a directory into a Simple Archive (default). JPK is applied automatically inside."
-                        );
-                    }
-                    packed_data = pack_folder(&input, FolderPackType::Simple);
+                    packed_data =
+                        pack_folder_with_options(&input, FolderPackType::Simple, &pack_options)?;
                 }
             } else {
-                if mha {
-                    panic!(
+                if mha || capacity.is_some() || baseid.is_some() {
+                    return Err(
                         "--mha, --capacity, --baseid flags can only be used when the input is a directory."
+                            .into(),
                     );
                 }
                 if em {
-                    panic!("--em cannot be used when packing a single file.");
+                    return Err("--em cannot be used when packing a single file.".into());
                 }
-                let file_buf = fs::read(&input).unwrap();
-                if let Some(jpk_type) = compression {
-                    match jpk_type {
-                        0 | 2 | 3 | 4 => {
-                            packed_data = pack_buffer(&file_buf, PackType::Jpk(jpk_type as u16));
-                        }
-                        _ => {
-                            panic!(
-                                "Invalid JPK compression type: {}. Valid types are 0, 2, 3, 4.",
-                                jpk_type
-                            );
-                        }
-                    }
+                let file_buf = fs::read(&input)?;
+                if let Some(jpk_selection) = jpk_selection {
+                    let pack_type = match jpk_selection {
+                        JpkSelection::Type(jpk_type) => PackType::Jpk(jpk_type),
+                        JpkSelection::Auto(candidates) => PackType::JpkAuto(candidates),
+                    };
+                    packed_data = pack_buffer_with_options(&file_buf, pack_type, &pack_options)?;
                 } else {
                     packed_data = file_buf;
                 }
@@ -192,36 +688,62 @@ fn main() {
                 );
             }
 
-            let out_data = if encrypt {
-                pack_buffer(&packed_data, PackType::Ecd)
-            } else {
-                packed_data
+            let encrypt = encrypt.filter(|_| {
+                let already_encrypted = is_buf_ecd(&packed_data) || is_buf_exf(&packed_data);
+                if input.is_dir() && already_encrypted {
+                    eprintln!(
+                        "Warning: The directory's .pack.json already encrypts the output, ignoring --encrypt."
+                    );
+                }
+                !(input.is_dir() && already_encrypted)
+            });
+            let out_data = match encrypt {
+                Some(Encryption::Ecd) => {
+                    let default_params = EcdParams::default();
+                    let params = EcdParams {
+                        index: key_index.unwrap_or(default_params.index),
+                        version: key_version.unwrap_or(default_params.version),
+                    };
+                    pack_buffer(&packed_data, PackType::Ecd(params))?
+                }
+                Some(Encryption::Exf) => {
+                    let default_params = ExfParams::default();
+                    let params = ExfParams {
+                        index: key_index.unwrap_or(default_params.index),
+                        version: key_version.unwrap_or(default_params.version),
+                    };
+                    pack_buffer(&packed_data, PackType::Exf(params))?
+                }
+                None => packed_data,
             };
 
             if let Some(path) = output {
                 if let Some(parent) = path.parent() {
-                    fs::create_dir_all(parent).unwrap();
+                    fs::create_dir_all(parent)?;
                 }
-                fs::write(path, out_data).unwrap();
+                fs::write(path, out_data)?;
             } else {
-                io::stdout().write_all(&out_data).unwrap();
+                io::stdout().write_all(&out_data)?;
             }
         }
         Commands::Unpack {
             input,
             output,
             decrypt,
+            manifest,
+            lenient,
+            detection,
+            jobs,
         } => {
             let output_path = if let Some(path) = output {
-                let derived_path = if path.is_dir() {
+                if path.is_dir() {
                     let mut new_path = path.clone();
                     let input_file_name = input.file_stem().unwrap_or_default();
                     new_path.push(input_file_name);
                     new_path
                 } else {
                     path
-                };
-                derived_path
+                }
             } else {
                 let stem = input
                     .file_stem()
@@ -231,26 +753,476 @@ fn main() {
                 PathBuf::from(stem)
             };
 
-            let file_buf = fs::read(&input).unwrap();
+            let file_buf = fs::read(&input)?;
             if decrypt {
+                if is_buf_exf(&file_buf) {
+                    let (header, decrypted_buf) = decrypt_exf_with_header(&file_buf)?;
+                    eprintln!("EXF key index {}, version {}", header.index, header.version);
+                    fs::write(&output_path, decrypted_buf)?;
+                    return Ok(());
+                }
                 if !is_buf_ecd(&file_buf) {
-                    panic!("Input file is not ECD encrypted.");
+                    return Err("Input file is not ECD or EXF encrypted.".into());
                 }
-                let decrypted_buf = decrypt_ecd(&file_buf);
-                fs::write(&output_path, decrypted_buf).unwrap();
-                return;
+                let (header, decrypted_buf) = decrypt_ecd_unchecked(&file_buf)?;
+                if let Err(e) = header.check_crc(&decrypted_buf) {
+                    if !lenient {
+                        return Err(e.into());
+                    }
+                    eprintln!("Warning: {}", e);
+                }
+                eprintln!("ECD key index {}, version {}", header.index, header.version);
+                fs::write(&output_path, decrypted_buf)?;
+                return Ok(());
+            }
+            let options = UnpackOptions {
+                lenient_crc: lenient,
+                jobs,
+                ..detection.into()
+            };
+            let output =
+                unpack_buffer_with_options(&output_path.to_string_lossy(), &file_buf, &options)?;
+
+            for warning in &output.warnings {
+                eprintln!("Warning: {}", warning);
             }
-            let unpacked_files = unpack_buffer(&output_path.to_string_lossy(), &file_buf);
 
-            for (path, buf) in unpacked_files {
+            for (path, buf) in output.files {
                 if let Some(parent) = path.parent() {
-                    fs::create_dir_all(parent).unwrap();
+                    fs::create_dir_all(parent)?;
                 }
 
-                fs::write(path, buf).unwrap();
+                fs::write(path, buf)?;
+            }
+
+            if manifest {
+                let path = manifest_path(&output_path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, output.manifest.to_json()?)?;
             }
         }
+        Commands::Diff {
+            old,
+            new,
+            json,
+            lenient,
+            jobs,
+            detection,
+        } => {
+            let old_buf = fs::read(&old)?;
+            let new_buf = fs::read(&new)?;
+            let name = old.file_stem().unwrap_or_default().to_string_lossy();
+            let options = UnpackOptions {
+                lenient_crc: lenient,
+                jobs,
+                ..detection.into()
+            };
+            let report = diff_buffers(&name, &old_buf, &new_buf, &options)?;
+
+            if json {
+                println!("{}", report.to_json()?);
+            } else {
+                for difference in &report.differences {
+                    println!("{}", describe_difference(difference));
+                }
+                println!("{} differences", report.differences.len());
+            }
+        }
+        Commands::Extract {
+            input,
+            entry,
+            output,
+            stop_at,
+            lenient,
+            detection,
+        } => {
+            let file_buf = fs::read(&input)?;
+            let name = input.file_stem().unwrap_or_default().to_string_lossy();
+            let options = UnpackOptions {
+                lenient_crc: lenient,
+                ..detection.into()
+            };
+            let stop_at = stop_at.map(StopAt::from).unwrap_or_default();
+            let out_data = extract_entry(&name, &file_buf, &entry, stop_at, &options)?;
+
+            if let Some(path) = output {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, out_data)?;
+            } else {
+                io::stdout().write_all(&out_data)?;
+            }
+        }
+        Commands::Replace {
+            input,
+            entry,
+            with,
+            output,
+            stop_at,
+            compression_level,
+            lenient,
+            detection,
+        } => {
+            let file_buf = fs::read(&input)?;
+            let replacement = fs::read(&with)?;
+            let name = input.file_stem().unwrap_or_default().to_string_lossy();
+            let unpack_options = UnpackOptions {
+                lenient_crc: lenient,
+                ..detection.into()
+            };
+            let pack_options = PackOptions {
+                compression: compression_level.into(),
+                ..Default::default()
+            };
+            let out_data = replace_entry(
+                &name,
+                &file_buf,
+                &entry,
+                &replacement,
+                stop_at.map(StopAt::from).unwrap_or_default(),
+                &unpack_options,
+                &pack_options,
+            )?;
+
+            if let Some(path) = output {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, out_data)?;
+            } else {
+                io::stdout().write_all(&out_data)?;
+            }
+        }
+        Commands::Repack {
+            input,
+            output,
+            compression_level,
+            jobs,
+        } => {
+            let manifest = Manifest::from_json(&fs::read_to_string(&input)?)?;
+            let root = input.parent().unwrap_or(Path::new(""));
+            let options = PackOptions {
+                compression: compression_level.into(),
+                jobs,
+                ..Default::default()
+            };
+            let out_data = repack_from_manifest_with_options(&manifest, root, &options)?;
+
+            if let Some(path) = output {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, out_data)?;
+            } else {
+                io::stdout().write_all(&out_data)?;
+            }
+        }
+        Commands::Verify {
+            input,
+            json,
+            strict,
+            compression_level,
+            lenient,
+            jobs,
+            detection,
+        } => {
+            let unpack_options = UnpackOptions {
+                lenient_crc: lenient,
+                jobs,
+                ..detection.into()
+            };
+            let pack_options = PackOptions {
+                compression: compression_level.into(),
+                jobs,
+                ..Default::default()
+            };
+            let report = verify_path(&input, &unpack_options, &pack_options)?;
+
+            if json {
+                println!("{}", report.to_json()?);
+            } else {
+                for node in &report.nodes {
+                    let location = if node.entry.is_empty() {
+                        node.file.clone()
+                    } else {
+                        format!("{} [{}]", node.file, node.entry)
+                    };
+                    match node.status {
+                        VerifyStatus::Identical => {}
+                        VerifyStatus::SameData => println!("SAME DATA  {}", location),
+                        VerifyStatus::Differs => println!("DIFFERS    {}", location),
+                        VerifyStatus::Failed => println!(
+                            "FAILED     {}: {}",
+                            location,
+                            node.error.as_deref().unwrap_or_default()
+                        ),
+                    }
+                }
+                println!(
+                    "{} nodes checked: {} identical, {} same data, {} differ, {} failed",
+                    report.nodes.len(),
+                    report.count(VerifyStatus::Identical),
+                    report.count(VerifyStatus::SameData),
+                    report.count(VerifyStatus::Differs),
+                    report.count(VerifyStatus::Failed)
+                );
+            }
+
+            let mut failed =
+                report.count(VerifyStatus::Differs) + report.count(VerifyStatus::Failed);
+            if strict {
+                failed += report.count(VerifyStatus::SameData);
+            }
+            if failed > 0 {
+                return Err(format!("{} nodes failed verification", failed).into());
+            }
+        }
+        Commands::Inspect {
+            input,
+            json,
+            detection,
+        } => {
+            let file_buf = fs::read(&input)?;
+            // Same prefix as `unpack` without --output, so --force-archive paths match
+            let name = input.file_stem().unwrap_or_default().to_string_lossy();
+            let node = inspect_buffer(&name, &file_buf, &detection.into())?;
+
+            if json {
+                println!("{}", node.to_json()?);
+            } else {
+                let file_name = input.file_name().unwrap_or_default().to_string_lossy();
+                println!("{}  {}", file_name, describe_node(&node));
+                print_entries(&node, "");
+            }
+        }
+        Commands::Text { command } => run_text(command)?,
     }
-    let duration = start.elapsed();
-    println!("Processed command in {:?}", duration);
+
+    Ok(())
+}
+
+/// One line per difference, prefixed with '+' for added, '-' for removed and '~' for
+/// changed nodes.
+fn describe_difference(difference: &Difference) -> String {
+    let path = if difference.path.is_empty() {
+        "."
+    } else {
+        &difference.path
+    };
+    let kind_name = |kind: &NodeKind| match kind {
+        NodeKind::File => "file",
+        NodeKind::SimpleArchive => "Simple Archive",
+        NodeKind::Mha => "MHA",
+    };
+    match &difference.change {
+        Change::Added { size } => format!("+ {}  added ({} bytes)", path, size),
+        Change::Removed { size } => format!("- {}  removed ({} bytes)", path, size),
+        Change::Layers { old, new } => format!(
+            "~ {}  layers: {} -> {}",
+            path,
+            describe_layers(old),
+            describe_layers(new)
+        ),
+        Change::Kind { old, new } => {
+            format!("~ {}  {} -> {}", path, kind_name(old), kind_name(new))
+        }
+        Change::Field { field, old, new } => format!("~ {}  {}: {} -> {}", path, field, old, new),
+        Change::Order { old, new } => format!(
+            "~ {}  order: {} -> {}",
+            path,
+            old.join(", "),
+            new.join(", ")
+        ),
+        Change::Layout => format!("~ {}  layout changed, same entries", path),
+        Change::Content {
+            old_size,
+            new_size,
+            first_difference,
+        } => format!(
+            "~ {}  content: {} -> {} bytes ({:+}), first difference at 0x{:X}",
+            path,
+            old_size,
+            new_size,
+            *new_size as i64 - *old_size as i64,
+            first_difference
+        ),
+    }
+}
+
+fn describe_layers(layers: &[Layer]) -> String {
+    if layers.is_empty() {
+        return "none".to_string();
+    }
+    layers
+        .iter()
+        .map(|layer| match layer {
+            Layer::Ecd { index, version } => {
+                format!("ECD (index {}, version {})", index, version)
+            }
+            Layer::Exf { index, version } => {
+                format!("EXF (index {}, version {})", index, version)
+            }
+            Layer::Jpk { jpk_type } => format!("JPK type {}", jpk_type),
+        })
+        .collect::<Vec<_>>()
+        .join(" > ")
+}
+
+/// One line summary of a node: its size, layers (outermost first) and content.
+fn describe_node(node: &InspectNode) -> String {
+    let mut parts = vec![format!("{} bytes", node.size)];
+    for layer in &node.layers {
+        parts.push(match layer {
+            LayerInfo::Ecd {
+                index,
+                version,
+                crc32,
+                crc_ok,
+            } => format!(
+                "ECD (index {}, version {}, CRC32 {:08X}{})",
+                index,
+                version,
+                crc32,
+                if *crc_ok { "" } else { " MISMATCH" }
+            ),
+            LayerInfo::Exf { index, version } => {
+                format!("EXF (index {}, version {})", index, version)
+            }
+            LayerInfo::Jpk {
+                jpk_type,
+                packed_size,
+                unpacked_size,
+                ratio,
+            } => format!(
+                "JPK type {} ({} -> {} bytes, {:.1}%)",
+                jpk_type,
+                packed_size,
+                unpacked_size,
+                ratio * 100.0
+            ),
+        });
+    }
+    parts.push(match &node.content {
+        InspectContent::File { extension } => format!("{} file", extension),
+        InspectContent::SimpleArchive { alignment, entries } => match alignment {
+            Some(alignment) => format!(
+                "Simple Archive ({} entries, alignment {})",
+                entries.len(),
+                alignment
+            ),
+            None => format!(
+                "Simple Archive ({} entries, irregular layout)",
+                entries.len()
+            ),
+        },
+        InspectContent::Mha {
+            base_id,
+            capacity,
+            entries,
+        } => format!(
+            "MHA ({} entries, base ID {}, capacity {})",
+            entries.len(),
+            base_id,
+            capacity
+        ),
+    });
+    parts.join(" > ")
+}
+
+/// Prints the entries of an archive node, and theirs, below it.
+fn print_entries(node: &InspectNode, indent: &str) {
+    let entries: Vec<(String, &InspectNode)> = match &node.content {
+        InspectContent::File { .. } => Vec::new(),
+        InspectContent::SimpleArchive { entries, .. } => entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (format!("{:04} @ 0x{:X}", i, entry.offset), &entry.node))
+            .collect(),
+        InspectContent::Mha { entries, .. } => entries
+            .iter()
+            .map(|entry| (format!("{} (ID {})", entry.name, entry.id), &entry.node))
+            .collect(),
+    };
+
+    for (i, (label, entry)) in entries.iter().enumerate() {
+        let last = i + 1 == entries.len();
+        let (branch, child_indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        println!("{}{}{}  {}", indent, branch, label, describe_node(entry));
+        print_entries(entry, &format!("{}{}", indent, child_indent));
+    }
+}
+
+fn run_text(command: TextCommands) -> Result<(), Box<dyn Error>> {
+    match command {
+        TextCommands::Export {
+            input,
+            output,
+            format,
+            scan,
+        } => {
+            let format = table_format(format, output.as_deref());
+            let file_buf = fs::read(&input)?;
+            let entries = if is_buf_ftxt(&file_buf) {
+                decode_ftxt(&file_buf)?.to_entries()
+            } else {
+                scan_strings(&file_buf, &scan.into()).to_entries()
+            };
+            let table = write_table(&entries, format)?;
+
+            if let Some(path) = output {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, table)?;
+            } else {
+                io::stdout().write_all(table.as_bytes())?;
+            }
+        }
+        TextCommands::Import {
+            input,
+            output,
+            original,
+            format,
+            scan,
+        } => {
+            let format = table_format(format, Some(&input));
+            let entries = read_table(&fs::read_to_string(&input)?, format)?;
+            let out_data = match original {
+                Some(original) => {
+                    let original_buf = fs::read(original)?;
+                    if is_buf_ftxt(&original_buf) {
+                        let mut ftxt = decode_ftxt(&original_buf)?;
+                        ftxt.apply_entries(&entries)?;
+                        encode_ftxt(&ftxt)?
+                    } else {
+                        let table = scan_strings(&original_buf, &scan.into());
+                        reinsert_strings(&original_buf, &table, &entries)?
+                    }
+                }
+                None => encode_ftxt(&Ftxt::from_entries(&entries)?)?,
+            };
+
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(output, out_data)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The format given on the command line, else the one matching `path`'s extension, else CSV.
+fn table_format(format: Option<TextFormat>, path: Option<&Path>) -> TableFormat {
+    format
+        .map(TableFormat::from)
+        .or_else(|| path.and_then(TableFormat::from_path))
+        .unwrap_or(TableFormat::Csv)
 }
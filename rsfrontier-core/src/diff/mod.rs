@@ -0,0 +1,456 @@
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    UnpackOptions,
+    entry::{Archive, StopAt, join_entry, mha_folder_names, strip_layers},
+    error::{Error, Result},
+    manifest::Layer,
+    mha::MhaEntry,
+    run_with_jobs,
+};
+
+/// Differences between two buffers, in tree order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DiffReport {
+    pub differences: Vec<Difference>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// Entry path of the node, as taken by `extract_entry`, empty for the whole buffer.
+    pub path: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// The entry is only in the new buffer, `size` is its stored size.
+    Added { size: usize },
+    /// The entry is only in the old buffer.
+    Removed { size: usize },
+    /// The node is wrapped in different layers, or the same layers with other parameters.
+    Layers { old: Vec<Layer>, new: Vec<Layer> },
+    /// The node was a file and is now an archive, or the other way around.
+    Kind { old: NodeKind, new: NodeKind },
+    /// An MHA header or entry field changed.
+    Field {
+        field: &'static str,
+        old: u32,
+        new: u32,
+    },
+    /// The entries both MHA archives have are stored in another order.
+    Order { old: Vec<String>, new: Vec<String> },
+    /// The archive's entries are the same but it is encoded differently (alignment,
+    /// padding, entries sharing data...).
+    Layout,
+    /// The data of a file changed, sizes are without layers.
+    Content {
+        old_size: usize,
+        new_size: usize,
+        /// Offset of the first differing byte, the shorter size when one is a prefix of the other.
+        first_difference: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    File,
+    SimpleArchive,
+    Mha,
+}
+
+impl NodeKind {
+    fn of(archive: &Option<Archive>) -> Self {
+        match archive {
+            None => NodeKind::File,
+            Some(Archive::Simple { .. }) => NodeKind::SimpleArchive,
+            Some(Archive::Mha { .. }) => NodeKind::Mha,
+        }
+    }
+}
+
+/// Compares `old` and `new` node by node, with the same detection as `unpack_buffer`.
+/// Simple Archive entries are paired by index and MHA entries by name, identical nodes
+/// are skipped without being decoded. `prefix_path` is used for both buffers to match
+/// `options.simple_archive_overrides`, see `inspect_buffer`.
+pub fn diff_buffers(
+    prefix_path: &str,
+    old: &[u8],
+    new: &[u8],
+    options: &UnpackOptions,
+) -> Result<DiffReport> {
+    let path = PathBuf::from(prefix_path);
+    let differ = Differ {
+        root: path.parent().unwrap_or(Path::new("")),
+        options,
+    };
+    run_with_jobs(options.jobs, || {
+        Ok(DiffReport {
+            differences: differ.diff_node(old, new, &path, "")?,
+        })
+    })
+}
+
+struct Differ<'a> {
+    root: &'a Path,
+    options: &'a UnpackOptions,
+}
+
+impl Differ<'_> {
+    /// `path` is where `unpack` would write the node, `entry` its entry path.
+    fn diff_node(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        path: &Path,
+        entry: &str,
+    ) -> Result<Vec<Difference>> {
+        if old == new {
+            return Ok(Vec::new());
+        }
+
+        let (old_layers, old_data) = strip_layers(old.to_vec(), path, StopAt::Data, self.options)?;
+        let (new_layers, new_data) = strip_layers(new.to_vec(), path, StopAt::Data, self.options)?;
+        let mut changes = Vec::new();
+        if old_layers != new_layers {
+            changes.push(Change::Layers {
+                old: old_layers,
+                new: new_layers,
+            });
+        }
+
+        let old_archive = Archive::detect(&old_data, path, self.root, self.options)
+            .map_err(|e| e.in_layer(path))?;
+        let new_archive = Archive::detect(&new_data, path, self.root, self.options)
+            .map_err(|e| e.in_layer(path))?;
+        let is_archive = old_archive.is_some() || new_archive.is_some();
+        let pairs = match (old_archive, new_archive) {
+            (None, None) => {
+                if old_data != new_data {
+                    let first_difference = old_data
+                        .iter()
+                        .zip(&new_data)
+                        .position(|(a, b)| a != b)
+                        .unwrap_or(old_data.len().min(new_data.len()));
+                    changes.push(Change::Content {
+                        old_size: old_data.len(),
+                        new_size: new_data.len(),
+                        first_difference,
+                    });
+                }
+                Vec::new()
+            }
+            (
+                Some(Archive::Simple { entries: old, .. }),
+                Some(Archive::Simple { entries: new, .. }),
+            ) => {
+                let count = old.len().max(new.len());
+                let mut old = old.into_iter().map(Some).chain(std::iter::repeat(None));
+                let mut new = new.into_iter().map(Some).chain(std::iter::repeat(None));
+                (0..count)
+                    .map(|i| {
+                        let name = format!("{:04}", i);
+                        let (old, new) = (old.next().flatten(), new.next().flatten());
+                        (path.join(&name), name, old, new, Vec::new())
+                    })
+                    .collect()
+            }
+            (
+                Some(Archive::Mha {
+                    base_id: old_base_id,
+                    capacity: old_capacity,
+                    entries: old,
+                }),
+                Some(Archive::Mha {
+                    base_id: new_base_id,
+                    capacity: new_capacity,
+                    entries: new,
+                }),
+            ) => {
+                for (field, old, new) in [
+                    ("base_id", old_base_id, new_base_id),
+                    ("capacity", old_capacity, new_capacity),
+                ] {
+                    if old != new {
+                        changes.push(Change::Field {
+                            field,
+                            old: old as u32,
+                            new: new as u32,
+                        });
+                    }
+                }
+
+                let old_order: Vec<_> = old
+                    .iter()
+                    .filter(|o| new.iter().any(|n| n.name == o.name))
+                    .map(|o| o.name.clone())
+                    .collect();
+                let new_order: Vec<_> = new
+                    .iter()
+                    .filter(|n| old.iter().any(|o| o.name == n.name))
+                    .map(|n| n.name.clone())
+                    .collect();
+                if old_order != new_order {
+                    changes.push(Change::Order {
+                        old: old_order,
+                        new: new_order,
+                    });
+                }
+
+                let old_folders = mha_folder_names(&old);
+                let new_folders = mha_folder_names(&new);
+                let mut new: Vec<_> = new.into_iter().zip(new_folders).map(Some).collect();
+                let mut pairs = Vec::new();
+                for (old_entry, old_folder) in old.into_iter().zip(old_folders) {
+                    let found = new
+                        .iter()
+                        .position(|e| e.as_ref().is_some_and(|(e, _)| e.name == old_entry.name));
+                    let new_entry = found.and_then(|i| new[i].take()).map(|(e, _)| e);
+                    let mut fields = Vec::new();
+                    if let Some(new_entry) = &new_entry {
+                        if old_entry.id != new_entry.id {
+                            fields.push(Change::Field {
+                                field: "id",
+                                old: old_entry.id,
+                                new: new_entry.id,
+                            });
+                        }
+                        // The padded size usually follows the data, already reported as content
+                        let padded = |e: &MhaEntry| e.padded_size != e.size;
+                        if (padded(&old_entry) || padded(new_entry))
+                            && old_entry.padded_size != new_entry.padded_size
+                        {
+                            fields.push(Change::Field {
+                                field: "padded_size",
+                                old: old_entry.padded_size,
+                                new: new_entry.padded_size,
+                            });
+                        }
+                    }
+                    pairs.push((
+                        path.join(old_folder),
+                        old_entry.name,
+                        Some(old_entry.data),
+                        new_entry.map(|e| e.data),
+                        fields,
+                    ));
+                }
+                for (new_entry, new_folder) in new.into_iter().flatten() {
+                    pairs.push((
+                        path.join(new_folder),
+                        new_entry.name,
+                        None,
+                        Some(new_entry.data),
+                        Vec::new(),
+                    ));
+                }
+                pairs
+            }
+            (old_archive, new_archive) => {
+                changes.push(Change::Kind {
+                    old: NodeKind::of(&old_archive),
+                    new: NodeKind::of(&new_archive),
+                });
+                Vec::new()
+            }
+        };
+
+        let children = pairs
+            .par_iter()
+            .map(|(child_path, name, old, new, fields)| {
+                let child_entry = join_entry(entry, name);
+                let mut differences: Vec<_> = fields
+                    .iter()
+                    .map(|change| Difference {
+                        path: child_entry.clone(),
+                        change: change.clone(),
+                    })
+                    .collect();
+                let change = match (old, new) {
+                    (Some(old), Some(new)) => {
+                        differences.extend(self.diff_node(old, new, child_path, &child_entry)?);
+                        None
+                    }
+                    (Some(old), None) => Some(Change::Removed { size: old.len() }),
+                    (None, Some(new)) => Some(Change::Added { size: new.len() }),
+                    (None, None) => None,
+                };
+                differences.extend(change.map(|change| Difference {
+                    path: child_entry,
+                    change,
+                }));
+                Ok(differences)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let children: Vec<_> = children.into_iter().flatten().collect();
+
+        // Same entries in a differently encoded archive
+        if is_archive && changes.is_empty() && children.is_empty() && old_data != new_data {
+            changes.push(Change::Layout);
+        }
+
+        let mut differences: Vec<_> = changes
+            .into_iter()
+            .map(|change| Difference {
+                path: entry.to_string(),
+                change,
+            })
+            .collect();
+        differences.extend(children);
+        Ok(differences)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        UnpackOptions, ecd,
+        jpk::create_jpk,
+        manifest::Layer,
+        mha::{MhaEntry, encode_mha_archive},
+        simple_archive::encode_simple_archive_aligned,
+    };
+
+    use super::{Change, Difference, NodeKind, diff_buffers};
+
+    fn archive(entries: &[MhaEntry], first: Vec<u8>, extra: Option<Vec<u8>>) -> Vec<u8> {
+        let mha = encode_mha_archive(entries, 3, 8).unwrap();
+        let mut files = vec![first, mha];
+        files.extend(extra);
+        ecd::encrypt_ecd(&encode_simple_archive_aligned(&files, 1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn changes_are_reported_by_path() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 7) as u8).collect();
+        let mut edited = data.clone();
+        edited[1500] = 0xFF;
+        let old = archive(
+            &[
+                MhaEntry::new("a.bin".to_string(), 3, create_jpk(&data, 3).unwrap()),
+                MhaEntry::new("b.bin".to_string(), 4, vec![1; 8]),
+                MhaEntry::new("c.bin".to_string(), 5, vec![2; 8]),
+            ],
+            vec![9; 4],
+            None,
+        );
+        let new = archive(
+            &[
+                MhaEntry::new("a.bin".to_string(), 3, create_jpk(&edited, 4).unwrap()),
+                MhaEntry::new("b.bin".to_string(), 6, vec![1; 8]),
+                MhaEntry::new("d.bin".to_string(), 7, vec![3; 2]),
+            ],
+            vec![9; 4],
+            Some(vec![5; 12]),
+        );
+
+        let options = UnpackOptions::default();
+        assert!(
+            diff_buffers("file", &old, &old, &options)
+                .unwrap()
+                .is_empty()
+        );
+
+        let report = diff_buffers("file", &old, &new, &options).unwrap();
+        let difference = |path: &str, change| Difference {
+            path: path.to_string(),
+            change,
+        };
+        assert_eq!(
+            report.differences,
+            vec![
+                difference(
+                    "0001/a.bin",
+                    Change::Layers {
+                        old: vec![Layer::Jpk { jpk_type: 3 }],
+                        new: vec![Layer::Jpk { jpk_type: 4 }],
+                    }
+                ),
+                difference(
+                    "0001/a.bin",
+                    Change::Content {
+                        old_size: 2000,
+                        new_size: 2000,
+                        first_difference: 1500
+                    }
+                ),
+                difference(
+                    "0001/b.bin",
+                    Change::Field {
+                        field: "id",
+                        old: 4,
+                        new: 6
+                    }
+                ),
+                difference("0001/c.bin", Change::Removed { size: 8 }),
+                difference("0001/d.bin", Change::Added { size: 2 }),
+                difference("0002", Change::Added { size: 12 }),
+            ]
+        );
+        assert!(report.to_json().unwrap().contains("\"change\": \"added\""));
+
+        let file = ecd::encrypt_ecd(&[1, 2, 3]).unwrap();
+        let report = diff_buffers("file", &old, &file, &options).unwrap();
+        assert_eq!(
+            report.differences,
+            vec![difference(
+                "",
+                Change::Kind {
+                    old: NodeKind::SimpleArchive,
+                    new: NodeKind::File
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn layout_and_order_changes_are_reported() {
+        let difference = |path: &str, change| Difference {
+            path: path.to_string(),
+            change,
+        };
+
+        let files = [vec![1; 3], vec![2; 5]];
+        let old = encode_simple_archive_aligned(&files, 1).unwrap();
+        let new = encode_simple_archive_aligned(&files, 16).unwrap();
+        // Too small to be detected on their own
+        let options = UnpackOptions {
+            simple_archive_overrides: [("file".to_string(), true)].into(),
+            ..Default::default()
+        };
+        let report = diff_buffers("file", &old, &new, &options).unwrap();
+        assert_eq!(report.differences, vec![difference("", Change::Layout)]);
+
+        let a = MhaEntry::new("a.bin".to_string(), 3, vec![1; 8]);
+        let b = MhaEntry::new("b.bin".to_string(), 4, vec![2; 8]);
+        let old = encode_mha_archive(&[a.clone(), b.clone()], 3, 8).unwrap();
+        let new = encode_mha_archive(&[b, a], 3, 8).unwrap();
+        let report = diff_buffers("file", &old, &new, &UnpackOptions::default()).unwrap();
+        assert_eq!(
+            report.differences,
+            vec![difference(
+                "",
+                Change::Order {
+                    old: vec!["a.bin".to_string(), "b.bin".to_string()],
+                    new: vec!["b.bin".to_string(), "a.bin".to_string()],
+                }
+            )]
+        );
+    }
+}
@@ -1,13 +1,59 @@
 use std::io::Cursor;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, WriteBytesExt};
 
+use crate::error::{Error, Format, ReadFormatExt, Result, get_slice};
+
+const ECD_MAGIC: u32 = 442786661;
+const ECD_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EcdHeader {
-    magic: u32,
-    index: u16,
-    version: u16,
-    file_size: u32,
-    crc32: u32,
+    pub magic: u32,
+    /// Index of the multiplier/increment pair in `RAND_BUFFER_ECD` used for the keystream.
+    pub index: u16,
+    pub version: u16,
+    pub file_size: u32,
+    /// CRC32 of the decrypted payload, also used to seed the keystream.
+    pub crc32: u32,
+}
+
+impl EcdHeader {
+    /// Checks a decrypted payload against the CRC32 stored in the header.
+    pub fn check_crc(&self, decrypted: &[u8]) -> Result<()> {
+        let actual = crc32fast::hash(decrypted);
+        if actual != self.crc32 {
+            return Err(Error::EcdCrcMismatch {
+                expected: self.crc32,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// The parameters needed to re-encrypt a file with the same header.
+    pub fn params(&self) -> EcdParams {
+        EcdParams {
+            index: self.index,
+            version: self.version,
+        }
+    }
+}
+
+/// Header fields chosen by the caller when encrypting, the rest is derived from the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcdParams {
+    pub index: u16,
+    pub version: u16,
+}
+
+impl Default for EcdParams {
+    fn default() -> Self {
+        EcdParams {
+            index: 4,
+            version: 31739,
+        }
+    }
 }
 
 const RAND_BUFFER_ECD: [u8; 48] = [
@@ -16,12 +62,16 @@ const RAND_BUFFER_ECD: [u8; 48] = [
     0x00, 0x19, 0x66, 0x0D, 0x00, 0x00, 0x00, 0x03, 0x7D, 0x2B, 0x89, 0xDD, 0x00, 0x00, 0x00, 0x01,
 ];
 
+/// Number of key indexes available in `RAND_BUFFER_ECD`, valid indexes are `0..ECD_KEY_COUNT`.
+/// EXF draws its keys from the same table.
+pub const ECD_KEY_COUNT: u16 = (RAND_BUFFER_ECD.len() / 8) as u16;
+
 fn load_uint_32(buffer: &[u8], offset: usize) -> u32 {
     let bytes = &buffer[offset..offset + 4];
     u32::from_be_bytes(bytes.try_into().expect("Slice with incorrect length"))
 }
 
-fn get_rnd_ecd(index: usize, seed: &mut u32) -> u32 {
+pub(crate) fn get_rnd_ecd(index: usize, seed: &mut u32) -> u32 {
     let multiplier_offset = 8 * index;
     let increment_offset = multiplier_offset + 4;
 
@@ -32,26 +82,61 @@ fn get_rnd_ecd(index: usize, seed: &mut u32) -> u32 {
     *seed
 }
 
-pub fn decrypt_ecd(buffer: &[u8]) -> Vec<u8> {
+pub fn parse_ecd_header(buffer: &[u8]) -> Result<EcdHeader> {
     let mut cursor = Cursor::new(buffer);
-    let mut out_vec = Vec::new();
 
     let header = EcdHeader {
-        magic: cursor.read_u32::<LittleEndian>().unwrap(),
-        index: cursor.read_u16::<LittleEndian>().unwrap(),
-        version: cursor.read_u16::<LittleEndian>().unwrap(),
-        file_size: cursor.read_u32::<LittleEndian>().unwrap(),
-        crc32: cursor.read_u32::<LittleEndian>().unwrap(),
+        magic: cursor.read_u32_for(Format::Ecd)?,
+        index: cursor.read_u16_for(Format::Ecd)?,
+        version: cursor.read_u16_for(Format::Ecd)?,
+        file_size: cursor.read_u32_for(Format::Ecd)?,
+        crc32: cursor.read_u32_for(Format::Ecd)?,
     };
 
+    if header.magic != ECD_MAGIC {
+        return Err(Error::malformed(Format::Ecd, 0, "bad magic"));
+    }
+
+    if header.index >= ECD_KEY_COUNT {
+        return Err(Error::InvalidKeyIndex {
+            format: Format::Ecd,
+            index: header.index,
+        });
+    }
+
+    Ok(header)
+}
+
+pub fn decrypt_ecd(buffer: &[u8]) -> Result<Vec<u8>> {
+    decrypt_ecd_with_header(buffer).map(|(_, out_vec)| out_vec)
+}
+
+/// Decrypts `buffer` and also returns its header, whose `params()` re-encrypt it identically.
+pub fn decrypt_ecd_with_header(buffer: &[u8]) -> Result<(EcdHeader, Vec<u8>)> {
+    let (header, out_vec) = decrypt_ecd_unchecked(buffer)?;
+    header.check_crc(&out_vec)?;
+    Ok((header, out_vec))
+}
+
+/// Decrypts without verifying the payload CRC, for salvaging data from damaged files.
+/// Use `EcdHeader::check_crc` on the result to find out whether it is intact.
+pub fn decrypt_ecd_unchecked(buffer: &[u8]) -> Result<(EcdHeader, Vec<u8>)> {
+    let header = parse_ecd_header(buffer)?;
+    let data = get_slice(
+        buffer,
+        ECD_HEADER_SIZE,
+        header.file_size as usize,
+        Format::Ecd,
+    )?;
+    let mut out_vec = Vec::with_capacity(data.len());
+
     let mut rnd = header.crc32.rotate_right(16) | 1;
     let mut xorpad = get_rnd_ecd(header.index as usize, &mut rnd);
     let mut r8 = xorpad as u8;
 
-    for _ in 0..header.file_size as usize {
+    for &data in data {
         xorpad = get_rnd_ecd(header.index as usize, &mut rnd);
 
-        let data: u8 = cursor.read_u8().unwrap();
         let mut r11 = (data ^ r8) as u32;
         let mut r12 = (r11 >> 4) & 0xFF;
 
@@ -67,30 +152,42 @@ pub fn decrypt_ecd(buffer: &[u8]) -> Vec<u8> {
         out_vec.push(r8);
     }
 
-    out_vec
+    Ok((header, out_vec))
 }
 
-pub fn encrypt_ecd(buffer: &[u8]) -> Vec<u8> {
-    let mut out_buf: Vec<u8> = Vec::new();
-    let mut cursor = Cursor::new(buffer);
+pub fn encrypt_ecd(buffer: &[u8]) -> Result<Vec<u8>> {
+    encrypt_ecd_with(buffer, EcdParams::default())
+}
+
+pub fn encrypt_ecd_with(buffer: &[u8], params: EcdParams) -> Result<Vec<u8>> {
+    if params.index >= ECD_KEY_COUNT {
+        return Err(Error::InvalidKeyIndex {
+            format: Format::Ecd,
+            index: params.index,
+        });
+    }
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(buffer.len() + ECD_HEADER_SIZE);
 
-    let file_size = buffer.len();
+    let file_size: u32 = buffer.len().try_into().map_err(|_| Error::TooLarge {
+        format: Format::Ecd,
+        size: buffer.len(),
+    })?;
     let crc32 = crc32fast::hash(buffer);
-    let index: u16 = 4;
+    let index = params.index;
 
-    out_buf.write_u32::<LittleEndian>(442786661).unwrap();
-    out_buf.write_u16::<LittleEndian>(4).unwrap();
-    out_buf.write_u16::<LittleEndian>(31739).unwrap();
-    out_buf.write_u32::<LittleEndian>(file_size as u32).unwrap();
-    out_buf.write_u32::<LittleEndian>(crc32).unwrap();
+    out_buf.write_u32::<LittleEndian>(ECD_MAGIC)?;
+    out_buf.write_u16::<LittleEndian>(index)?;
+    out_buf.write_u16::<LittleEndian>(params.version)?;
+    out_buf.write_u32::<LittleEndian>(file_size)?;
+    out_buf.write_u32::<LittleEndian>(crc32)?;
 
     let mut rnd = crc32.rotate_right(16) | 1;
     let mut xorpad = get_rnd_ecd(index as usize, &mut rnd);
     let mut r8 = xorpad as u8;
 
-    for _ in 0..file_size {
+    for &data in buffer {
         xorpad = get_rnd_ecd(index as usize, &mut rnd);
-        let data = cursor.read_u8().unwrap();
 
         let mut r11 = 0;
         let mut r12 = 0;
@@ -115,7 +212,7 @@ pub fn encrypt_ecd(buffer: &[u8]) -> Vec<u8> {
         r8 = data;
     }
 
-    out_buf
+    Ok(out_buf)
 }
 
 pub fn is_buf_ecd(buffer: &[u8]) -> bool {
@@ -126,23 +223,26 @@ pub fn is_buf_ecd(buffer: &[u8]) -> bool {
             .try_into()
             .unwrap_or_default(),
     );
-    magic == 442786661
+    magic == ECD_MAGIC
 }
 
 #[cfg(test)]
 mod test {
     use std::fs;
 
-    use crate::ecd::decrypt_ecd;
+    use crate::{ecd::decrypt_ecd, error::Error};
 
-    use super::encrypt_ecd;
+    use super::{
+        ECD_KEY_COUNT, EcdParams, decrypt_ecd_unchecked, decrypt_ecd_with_header, encrypt_ecd,
+        encrypt_ecd_with,
+    };
 
     #[test]
     fn decrypting_ecd() {
         let encrypted_buf = fs::read("./tests/data/mhfdat.bin").unwrap();
         let decrypted_buf = fs::read("./tests/data/mhfdat_decrypted_only.bin").unwrap();
 
-        let custom_decrypted_buf = decrypt_ecd(&encrypted_buf);
+        let custom_decrypted_buf = decrypt_ecd(&encrypted_buf).unwrap();
 
         assert_eq!(custom_decrypted_buf, decrypted_buf);
     }
@@ -152,11 +252,83 @@ mod test {
         let encrypted_buf = fs::read("./tests/data/mhfdat.bin").unwrap();
         let decrypted_buf = fs::read("./tests/data/mhfdat_decrypted_only.bin").unwrap();
 
-        let custom_encrypted_buf = encrypt_ecd(&decrypted_buf);
+        let custom_encrypted_buf = encrypt_ecd(&decrypted_buf).unwrap();
 
         assert!(
             custom_encrypted_buf == encrypted_buf,
             "The buffers don't match"
         );
     }
+
+    #[test]
+    fn ecd_roundtrip() {
+        let data = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let encrypted = encrypt_ecd(&data).unwrap();
+        assert_eq!(decrypt_ecd(&encrypted).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated_ecd_is_an_error() {
+        let data = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let encrypted = encrypt_ecd(&data).unwrap();
+
+        assert!(decrypt_ecd(&encrypted[..10]).is_err());
+        assert!(decrypt_ecd(&encrypted[..encrypted.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn ecd_params_are_preserved() {
+        let data = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+
+        for index in 0..ECD_KEY_COUNT {
+            let params = EcdParams {
+                index,
+                version: 1234,
+            };
+            let encrypted = encrypt_ecd_with(&data, params).unwrap();
+            let (header, decrypted) = decrypt_ecd_with_header(&encrypted).unwrap();
+
+            assert_eq!(header.params(), params);
+            assert_eq!(decrypted, data);
+            assert_eq!(
+                encrypt_ecd_with(&decrypted, header.params()).unwrap(),
+                encrypted
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_range_key_index_is_rejected() {
+        let params = EcdParams {
+            index: ECD_KEY_COUNT,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encrypt_ecd_with(&[1, 2, 3], params),
+            Err(Error::InvalidKeyIndex { index: 6, .. })
+        ));
+
+        let mut encrypted = encrypt_ecd(&[1, 2, 3]).unwrap();
+        encrypted[4] = 9;
+        assert!(matches!(
+            decrypt_ecd(&encrypted),
+            Err(Error::InvalidKeyIndex { index: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn crc_mismatch_is_detected() {
+        let data = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let mut encrypted = encrypt_ecd(&data).unwrap();
+        encrypted[100] ^= 0x40;
+
+        assert!(matches!(
+            decrypt_ecd(&encrypted),
+            Err(Error::EcdCrcMismatch { .. })
+        ));
+
+        let (header, decrypted) = decrypt_ecd_unchecked(&encrypted).unwrap();
+        assert_eq!(decrypted.len(), data.len());
+        assert!(header.check_crc(&decrypted).is_err());
+    }
 }
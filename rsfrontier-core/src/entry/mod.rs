@@ -0,0 +1,517 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    PackOptions, Stripped, UnpackOptions, apply_layers,
+    error::{Error, Result},
+    manifest::{Layer, to_manifest_path},
+    mha::{MhaEntry, decode_mha_archive, encode_mha_archive, is_buf_mha, read_header_ids},
+    simple_archive::{
+        SimpleArchiveLayout, decode_simple_archive, detect_layout,
+        encode_simple_archive_with_layout,
+    },
+    strip_layer,
+};
+
+/// Where `extract_entry` stops removing the layers of the entry it returns, and which layers
+/// `replace_entry` re-applies to the replacement.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StopAt {
+    /// Every layer is removed, like the file `unpack` writes.
+    #[default]
+    Data,
+    /// The entry as stored in its archive, with all of its layers.
+    Stored,
+    /// Before the first ECD layer.
+    Ecd,
+    /// Before the first EXF layer.
+    Exf,
+    /// Before the first JPK layer, the entry is decrypted but still compressed.
+    Jpk,
+}
+
+impl StopAt {
+    fn stops_at(self, stripped: &Stripped) -> bool {
+        matches!(
+            (self, stripped),
+            (StopAt::Stored, _)
+                | (StopAt::Ecd, Stripped::Ecd(..))
+                | (StopAt::Exf, Stripped::Exf(..))
+                | (StopAt::Jpk, Stripped::Jpk(..))
+        )
+    }
+}
+
+/// The entries of an archive found while walking an entry path, with what is needed to
+/// encode it again.
+pub(crate) enum Archive {
+    Simple {
+        layout: SimpleArchiveLayout,
+        entries: Vec<Vec<u8>>,
+    },
+    Mha {
+        base_id: u16,
+        capacity: u16,
+        entries: Vec<MhaEntry>,
+    },
+}
+
+impl Archive {
+    /// Decodes `data` if `unpack` would split it, `path` is where `unpack` would have written it.
+    pub(crate) fn detect(
+        data: &[u8],
+        path: &Path,
+        root: &Path,
+        options: &UnpackOptions,
+    ) -> Result<Option<Self>> {
+        if options.is_simple_archive(data, &to_manifest_path(path, root)) {
+            return Ok(Some(Archive::Simple {
+                layout: detect_layout(data)?,
+                entries: decode_simple_archive(data)?,
+            }));
+        }
+        if is_buf_mha(data) {
+            let (base_id, capacity) = read_header_ids(data)?;
+            return Ok(Some(Archive::Mha {
+                base_id,
+                capacity,
+                entries: decode_mha_archive(data)?,
+            }));
+        }
+        Ok(None)
+    }
+
+    fn open(data: &[u8], path: &Path, root: &Path, options: &UnpackOptions) -> Result<Self> {
+        Archive::detect(data, path, root, options)?
+            .ok_or_else(|| Error::Entry("not an archive".to_string()))
+    }
+
+    /// Finds the entry named `segment`. Simple Archive entries are named by their index,
+    /// MHA entries by their name (with or without extension), the name `unpack` gives their
+    /// file, or index. Returns the entry's index and the name `unpack` gives its folder.
+    fn find(&self, segment: &str) -> Result<(usize, String)> {
+        let stem = name_stem(segment);
+        let index = (!stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| stem.parse::<usize>().ok())
+            .flatten()
+            .filter(|&i| i < self.len());
+        let mut names = self.entry_names();
+        let found = match self {
+            Archive::Simple { .. } => index,
+            Archive::Mha { entries, .. } => entries
+                .iter()
+                .position(|entry| entry.name == segment)
+                // The segment may be a folder, or a file with its detected extension
+                .or_else(|| {
+                    names
+                        .iter()
+                        .position(|(folder, _)| folder == segment || folder == stem)
+                })
+                .or_else(|| {
+                    entries.iter().position(|entry| {
+                        let entry_stem = name_stem(&entry.name);
+                        entry_stem == segment || entry_stem == stem
+                    })
+                })
+                .or(index),
+        };
+        found.map(|i| (i, names.swap_remove(i).0)).ok_or_else(|| {
+            Error::Entry(format!(
+                "no entry '{}' in the archive, it has {} entries",
+                segment,
+                self.len()
+            ))
+        })
+    }
+
+    /// The name `unpack` gives the folder of each entry, and the entry's name in entry paths.
+    pub(crate) fn entry_names(&self) -> Vec<(String, String)> {
+        match self {
+            Archive::Simple { entries, .. } => (0..entries.len())
+                .map(|i| {
+                    let name = format!("{:04}", i);
+                    (name.clone(), name)
+                })
+                .collect(),
+            Archive::Mha { entries, .. } => mha_folder_names(entries)
+                .into_iter()
+                .zip(entries)
+                .map(|(folder, entry)| (folder, entry.name.clone()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Archive::Simple { entries, .. } => entries.len(),
+            Archive::Mha { entries, .. } => entries.len(),
+        }
+    }
+
+    pub(crate) fn entry(&self, index: usize) -> &[u8] {
+        match self {
+            Archive::Simple { entries, .. } => &entries[index],
+            Archive::Mha { entries, .. } => &entries[index].data,
+        }
+    }
+
+    pub(crate) fn entry_mut(&mut self, index: usize) -> &mut Vec<u8> {
+        match self {
+            Archive::Simple { entries, .. } => &mut entries[index],
+            Archive::Mha { entries, .. } => &mut entries[index].data,
+        }
+    }
+
+    /// Encodes the archive with its original layout, or header IDs and entry fields.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            Archive::Simple { layout, entries } => {
+                encode_simple_archive_with_layout(entries, layout)
+            }
+            Archive::Mha {
+                base_id,
+                capacity,
+                entries,
+            } => encode_mha_archive(entries, *base_id, *capacity),
+        }
+    }
+}
+
+/// The file name `unpack` gives an MHA entry, before its extension is detected.
+pub(crate) fn name_stem(name: &str) -> &str {
+    Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name)
+}
+
+/// The names `unpack` gives the folders of MHA `entries`, before extensions are detected.
+/// Entries whose names only differ in extension get their index appended, so that none of
+/// them overwrites another on disk.
+pub(crate) fn mha_folder_names(entries: &[MhaEntry]) -> Vec<String> {
+    // Compared without case, the files may be unpacked on a case-insensitive file system
+    let mut stem_counts: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        *stem_counts
+            .entry(name_stem(&entry.name).to_lowercase())
+            .or_default() += 1;
+    }
+
+    let mut used = HashSet::new();
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let stem = name_stem(&entry.name);
+            let mut folder = if stem_counts[&stem.to_lowercase()] > 1 {
+                format!("{}_{}", stem, i)
+            } else {
+                stem.to_string()
+            };
+            while !used.insert(folder.to_lowercase()) {
+                folder.push('_');
+            }
+            folder
+        })
+        .collect()
+}
+
+/// Removes the layers of `buf` until `stop_at`, returning the removed layers outermost first.
+/// ECD payloads must match their CRC unless `options.lenient_crc` is set.
+pub(crate) fn strip_layers(
+    buf: Vec<u8>,
+    path: &Path,
+    stop_at: StopAt,
+    options: &UnpackOptions,
+) -> Result<(Vec<Layer>, Vec<u8>)> {
+    strip_layers_with(buf, path, stop_at, |stripped, _| {
+        if let Stripped::Ecd(header, decrypted) = stripped
+            && !options.lenient_crc
+        {
+            header.check_crc(decrypted)?;
+        }
+        Ok(())
+    })
+}
+
+/// Like `strip_layers`, but leaves ECD CRCs to `on_layer`, which is given every removed
+/// layer with the buffer it was removed from. Its errors stop the stripping.
+pub(crate) fn strip_layers_with(
+    buf: Vec<u8>,
+    path: &Path,
+    stop_at: StopAt,
+    mut on_layer: impl FnMut(&Stripped, &[u8]) -> Result<()>,
+) -> Result<(Vec<Layer>, Vec<u8>)> {
+    let mut processed_buffer = buf;
+    let mut layers = Vec::new();
+    while let Some(stripped) = strip_layer(&processed_buffer).map_err(|e| e.in_layer(path))? {
+        if stop_at.stops_at(&stripped) {
+            break;
+        }
+        on_layer(&stripped, &processed_buffer).map_err(|e| e.in_layer(path))?;
+        layers.push(stripped.layer());
+        processed_buffer = stripped.into_data();
+    }
+    Ok((layers, processed_buffer))
+}
+
+/// Splits an entry path into the names of the archive entries it goes through.
+fn entry_segments(entry: &str) -> Vec<&str> {
+    entry.split(['/', '\\']).filter(|s| !s.is_empty()).collect()
+}
+
+/// Appends `name` to the entry path `parent`, empty for the root.
+pub(crate) fn join_entry(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Returns a single nested entry of `buf` without unpacking the rest. `entry` is a '/'
+/// separated path through the archives, as laid out by `unpack` (e.g. `0003/0012`), where
+/// MHA entries can also be named. `prefix_path` is the path `unpack_buffer_with_options`
+/// would be given, it is used to match `options.simple_archive_overrides` and in errors.
+pub fn extract_entry(
+    prefix_path: &str,
+    buf: &[u8],
+    entry: &str,
+    stop_at: StopAt,
+    options: &UnpackOptions,
+) -> Result<Vec<u8>> {
+    let mut path = PathBuf::from(prefix_path);
+    let root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut current_buffer = buf.to_vec();
+
+    for segment in entry_segments(entry) {
+        let (_, data) = strip_layers(current_buffer, &path, StopAt::Data, options)?;
+        let mut archive =
+            Archive::open(&data, &path, &root, options).map_err(|e| e.in_layer(&path))?;
+        let (index, name) = archive.find(segment).map_err(|e| e.in_layer(&path))?;
+        path.push(name);
+        current_buffer = std::mem::take(archive.entry_mut(index));
+    }
+
+    strip_layers(current_buffer, &path, stop_at, options).map(|(_, data)| data)
+}
+
+/// Returns `buf` with the entry at `entry` (see `extract_entry`) replaced by `replacement`.
+/// Only the archives and layers leading to the entry are decoded, each is encoded again with
+/// the parameters it was read with (ECD/EXF key, JPK type, Simple Archive layout, MHA IDs),
+/// so sibling entries are left byte-identical. The entry's own layers are re-applied down to
+/// `stop_at`, which should match the one the entry was extracted with.
+pub fn replace_entry(
+    prefix_path: &str,
+    buf: &[u8],
+    entry: &str,
+    replacement: &[u8],
+    stop_at: StopAt,
+    unpack_options: &UnpackOptions,
+    pack_options: &PackOptions,
+) -> Result<Vec<u8>> {
+    let path = PathBuf::from(prefix_path);
+    let replace = EntryReplacement {
+        replacement,
+        stop_at,
+        root: path.parent().unwrap_or(Path::new("")),
+        unpack_options,
+        pack_options,
+    };
+    replace.replace_in(buf.to_vec(), &entry_segments(entry), path.clone())
+}
+
+struct EntryReplacement<'a> {
+    replacement: &'a [u8],
+    stop_at: StopAt,
+    root: &'a Path,
+    unpack_options: &'a UnpackOptions,
+    pack_options: &'a PackOptions,
+}
+
+impl EntryReplacement<'_> {
+    /// Rebuilds `buf` with the replacement put at `segments`, `path` is where `unpack` would
+    /// have written `buf`.
+    fn replace_in(&self, buf: Vec<u8>, segments: &[&str], path: PathBuf) -> Result<Vec<u8>> {
+        let Some((segment, rest)) = segments.split_first() else {
+            let (layers, _) = strip_layers(buf, &path, self.stop_at, self.unpack_options)?;
+            return apply_layers(self.replacement.to_vec(), &layers, self.pack_options)
+                .map_err(|e| e.in_layer(&path));
+        };
+
+        let (layers, data) = strip_layers(buf, &path, StopAt::Data, self.unpack_options)?;
+        let mut archive = Archive::open(&data, &path, self.root, self.unpack_options)
+            .map_err(|e| e.in_layer(&path))?;
+        let (index, name) = archive.find(segment).map_err(|e| e.in_layer(&path))?;
+        let entry = archive.entry_mut(index);
+        *entry = self.replace_in(std::mem::take(entry), rest, path.join(name))?;
+
+        let archive_buf = archive.encode().map_err(|e| e.in_layer(&path))?;
+        apply_layers(archive_buf, &layers, self.pack_options).map_err(|e| e.in_layer(&path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        PackOptions, UnpackOptions, ecd,
+        error::Error,
+        inspect::inspect_buffer,
+        jpk::create_jpk,
+        mha::{MhaEntry, decode_mha_archive, encode_mha_archive},
+        simple_archive::{decode_simple_archive, encode_simple_archive_aligned},
+    };
+
+    use super::{StopAt, extract_entry, replace_entry};
+
+    /// An ECD encrypted Simple Archive holding an MHA archive, whose entry `b.bin` is ECD
+    /// encrypted and JPK compressed.
+    struct Sample {
+        buf: Vec<u8>,
+        archive: Vec<u8>,
+        /// `b.bin` as stored, compressed and uncompressed.
+        encrypted: Vec<u8>,
+        compressed: Vec<u8>,
+        uncomp: Vec<u8>,
+    }
+
+    fn sample() -> Sample {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let compressed = create_jpk(&uncomp, 3).unwrap();
+        let encrypted = ecd::encrypt_ecd(&compressed).unwrap();
+        let mha = encode_mha_archive(
+            &[
+                MhaEntry::new("a.bin".to_string(), 3, vec![7; 8]),
+                MhaEntry {
+                    name: "b.bin".to_string(),
+                    id: 9,
+                    size: encrypted.len() as u32,
+                    padded_size: 0x10000,
+                    data: encrypted.clone(),
+                },
+            ],
+            3,
+            8,
+        )
+        .unwrap();
+        let archive = encode_simple_archive_aligned(&[vec![1; 4], mha], 1).unwrap();
+        let buf = ecd::encrypt_ecd(&archive).unwrap();
+        Sample {
+            buf,
+            archive,
+            encrypted,
+            compressed,
+            uncomp,
+        }
+    }
+
+    #[test]
+    fn nested_entry_is_extracted() {
+        let Sample {
+            buf,
+            archive,
+            encrypted,
+            compressed,
+            uncomp,
+        } = sample();
+
+        let options = UnpackOptions::default();
+        let extract = |entry, stop_at| extract_entry("file", &buf, entry, stop_at, &options);
+        assert_eq!(extract("0001/b", StopAt::Data).unwrap(), uncomp);
+        assert_eq!(extract("1/b.bin", StopAt::Data).unwrap(), uncomp);
+        assert_eq!(extract("0001/1", StopAt::Data).unwrap(), uncomp);
+        assert_eq!(extract("0001/b", StopAt::Stored).unwrap(), encrypted);
+        assert_eq!(extract("0001/b", StopAt::Jpk).unwrap(), compressed);
+        assert_eq!(extract("0001/a", StopAt::Data).unwrap(), vec![7; 8]);
+        assert_eq!(extract("", StopAt::Data).unwrap(), archive);
+
+        let missing = extract("0001/c", StopAt::Data).unwrap_err();
+        assert!(
+            matches!(missing, Error::Layer { source, .. } if matches!(*source, Error::Entry(_)))
+        );
+        assert!(extract("0000/0000", StopAt::Data).is_err());
+        assert!(extract("0001/1x", StopAt::Data).is_err());
+    }
+
+    #[test]
+    fn multi_dot_names_are_found() {
+        let entries = [
+            MhaEntry::new("model.v2.bin".to_string(), 3, vec![1; 8]),
+            MhaEntry::new("a.bin".to_string(), 4, vec![2; 8]),
+            MhaEntry::new("a.dat".to_string(), 5, vec![3; 8]),
+        ];
+        let buf = encode_mha_archive(&entries, 3, 8).unwrap();
+
+        let options = UnpackOptions::default();
+        let extract = |entry| extract_entry("file", &buf, entry, StopAt::Data, &options);
+        assert_eq!(extract("model.v2").unwrap(), vec![1; 8]);
+        assert_eq!(extract("model.v2.bin").unwrap(), vec![1; 8]);
+        assert_eq!(extract("a_2").unwrap(), vec![3; 8]);
+        assert_eq!(extract("a.dat").unwrap(), vec![3; 8]);
+        assert_eq!(extract("1").unwrap(), vec![2; 8]);
+    }
+    #[test]
+    fn nested_entry_is_replaced() {
+        let Sample { buf, uncomp, .. } = sample();
+        let options = UnpackOptions::default();
+        let replacement = uncomp[..1000].to_vec();
+
+        let replaced = replace_entry(
+            "file",
+            &buf,
+            "0001/b",
+            &replacement,
+            StopAt::Data,
+            &options,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let extract = |buf, entry| extract_entry("file", buf, entry, StopAt::Data, &options);
+        assert_eq!(extract(&replaced, "0001/b").unwrap(), replacement);
+        assert_eq!(
+            extract(&replaced, "0000").unwrap(),
+            extract(&buf, "0000").unwrap()
+        );
+
+        // Every layer and entry field is kept
+        let before = inspect_buffer("file", &buf, &options).unwrap();
+        let after = inspect_buffer("file", &replaced, &options).unwrap();
+        assert_eq!(after.layers.len(), before.layers.len());
+        let mha = extract(&replaced, "0001").unwrap();
+        let entries = decode_mha_archive(&mha).unwrap();
+        assert_eq!(
+            entries[0],
+            MhaEntry::new("a.bin".to_string(), 3, vec![7; 8])
+        );
+        assert_eq!((entries[1].id, entries[1].padded_size), (9, 0x10000));
+        let stored = extract_entry("file", &replaced, "0001/b", StopAt::Jpk, &options).unwrap();
+        assert_eq!(&stored[..4], b"JKR\x1A");
+        assert_eq!(
+            decode_simple_archive(&extract(&replaced, "").unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // A replacement given as stored is used as is
+        let raw = replace_entry(
+            "file",
+            &buf,
+            "0000",
+            &[2; 6],
+            StopAt::Stored,
+            &options,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(extract(&raw, "0000").unwrap(), vec![2; 6]);
+        assert_eq!(
+            extract(&raw, "0001").unwrap(),
+            extract(&buf, "0001").unwrap()
+        );
+    }
+}
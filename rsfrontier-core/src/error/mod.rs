@@ -0,0 +1,210 @@
+use std::{
+    fmt,
+    io::{self, Cursor},
+    path::PathBuf,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ecd,
+    Exf,
+    Jpk,
+    SimpleArchive,
+    Mha,
+    Ftxt,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Ecd => write!(f, "ECD"),
+            Format::Exf => write!(f, "EXF"),
+            Format::Jpk => write!(f, "JPK"),
+            Format::SimpleArchive => write!(f, "Simple Archive"),
+            Format::Mha => write!(f, "MHA"),
+            Format::Ftxt => write!(f, "FTXT"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The buffer ended before a field of the given format could be read.
+    Truncated {
+        format: Format,
+        offset: usize,
+    },
+    /// A field was read but its value makes no sense for the format.
+    Malformed {
+        format: Format,
+        offset: usize,
+        reason: String,
+    },
+    /// The buffer is too large to be described by the format's 32 bit size fields.
+    TooLarge {
+        format: Format,
+        size: usize,
+    },
+    InvalidJpkType(u16),
+    /// The ECD/EXF key index has no entry in the key table.
+    InvalidKeyIndex {
+        format: Format,
+        index: u16,
+    },
+    /// The decrypted ECD payload doesn't match the CRC32 stored in its header.
+    EcdCrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// An unpack manifest could not be read, written or doesn't match the files on disk.
+    Manifest(String),
+    /// A CSV/TSV text table could not be read, or its text can't be stored in the game's encoding.
+    Text(String),
+    /// An entry path doesn't lead to an entry of the archive.
+    Entry(String),
+    /// Wraps an error with the path of the node that was being processed.
+    Layer {
+        path: PathBuf,
+        source: Box<Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn malformed(format: Format, offset: usize, reason: impl Into<String>) -> Self {
+        Error::Malformed {
+            format,
+            offset,
+            reason: reason.into(),
+        }
+    }
+
+    /// Attaches the path of the node being processed, unless a deeper layer already did.
+    pub(crate) fn in_layer(self, path: impl Into<PathBuf>) -> Self {
+        match self {
+            Error::Layer { .. } => self,
+            _ => Error::Layer {
+                path: path.into(),
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// Rebases offsets reported relative to a sub buffer onto the enclosing buffer.
+    pub(crate) fn offset_by(self, base: usize) -> Self {
+        match self {
+            Error::Truncated { format, offset } => Error::Truncated {
+                format,
+                offset: offset + base,
+            },
+            Error::Malformed {
+                format,
+                offset,
+                reason,
+            } => Error::Malformed {
+                format,
+                offset: offset + base,
+                reason,
+            },
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O Error {}", err),
+            Error::Truncated { format, offset } => {
+                write!(f, "Truncated {} data at offset {:#x}", format, offset)
+            }
+            Error::Malformed {
+                format,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Malformed {} data at offset {:#x}: {}",
+                format, offset, reason
+            ),
+            Error::TooLarge { format, size } => {
+                write!(f, "Buffer of {} bytes is too large for {}", size, format)
+            }
+            Error::InvalidJpkType(val) => write!(f, "Invalid JPK Type Found {}", val),
+            Error::InvalidKeyIndex { format, index } => write!(
+                f,
+                "Invalid {} key index {}, valid indexes are 0 to {}",
+                format,
+                index,
+                crate::ecd::ECD_KEY_COUNT - 1
+            ),
+            Error::EcdCrcMismatch { expected, actual } => write!(
+                f,
+                "ECD CRC32 mismatch, header says {:08X} but data hashes to {:08X}",
+                expected, actual
+            ),
+            Error::Manifest(reason) => write!(f, "Invalid manifest: {}", reason),
+            Error::Text(reason) => write!(f, "Invalid text: {}", reason),
+            Error::Entry(reason) => write!(f, "Entry not found: {}", reason),
+            Error::Layer { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Layer { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+/// Little endian reads that report truncation with the format and offset they failed at.
+pub(crate) trait ReadFormatExt {
+    fn read_u8_for(&mut self, format: Format) -> Result<u8>;
+    fn read_u16_for(&mut self, format: Format) -> Result<u16>;
+    fn read_u32_for(&mut self, format: Format) -> Result<u32>;
+}
+
+impl ReadFormatExt for Cursor<&[u8]> {
+    fn read_u8_for(&mut self, format: Format) -> Result<u8> {
+        let offset = self.position() as usize;
+        self.read_u8()
+            .map_err(|_| Error::Truncated { format, offset })
+    }
+
+    fn read_u16_for(&mut self, format: Format) -> Result<u16> {
+        let offset = self.position() as usize;
+        self.read_u16::<LittleEndian>()
+            .map_err(|_| Error::Truncated { format, offset })
+    }
+
+    fn read_u32_for(&mut self, format: Format) -> Result<u32> {
+        let offset = self.position() as usize;
+        self.read_u32::<LittleEndian>()
+            .map_err(|_| Error::Truncated { format, offset })
+    }
+}
+
+/// Bounds checked `&buf[offset..offset + len]`.
+pub(crate) fn get_slice(buf: &[u8], offset: usize, len: usize, format: Format) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| buf.get(offset..end))
+        .ok_or(Error::Truncated {
+            format,
+            offset: offset.min(buf.len()),
+        })
+}
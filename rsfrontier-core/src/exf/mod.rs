@@ -0,0 +1,229 @@
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{
+    ecd::{ECD_KEY_COUNT, get_rnd_ecd},
+    error::{Error, Format, ReadFormatExt, Result, get_slice},
+};
+
+const EXF_MAGIC: u32 = 0x1A667865;
+const EXF_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExfHeader {
+    pub magic: u32,
+    /// Index of the multiplier/increment pair in the ECD key table used to build the key.
+    pub index: u16,
+    pub version: u16,
+    pub file_size: u32,
+    /// Seed of the 16 byte key, the encrypter uses the CRC32 of the payload.
+    pub seed: u32,
+}
+
+impl ExfHeader {
+    /// The parameters needed to re-encrypt a file with the same header.
+    pub fn params(&self) -> ExfParams {
+        ExfParams {
+            index: self.index,
+            version: self.version,
+        }
+    }
+}
+
+/// Header fields chosen by the caller when encrypting, the rest is derived from the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExfParams {
+    pub index: u16,
+    pub version: u16,
+}
+
+impl Default for ExfParams {
+    fn default() -> Self {
+        ExfParams {
+            index: 4,
+            version: 31739,
+        }
+    }
+}
+
+fn create_key(index: u16, seed: u32) -> [u8; 16] {
+    let mut key = [0; 16];
+    let mut rnd = seed;
+    for chunk in key.chunks_exact_mut(4) {
+        let value = get_rnd_ecd(index as usize, &mut rnd);
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    key
+}
+
+fn check_index(index: u16) -> Result<()> {
+    if index >= ECD_KEY_COUNT {
+        return Err(Error::InvalidKeyIndex {
+            format: Format::Exf,
+            index,
+        });
+    }
+    Ok(())
+}
+
+pub fn parse_exf_header(buffer: &[u8]) -> Result<ExfHeader> {
+    let mut cursor = Cursor::new(buffer);
+
+    let header = ExfHeader {
+        magic: cursor.read_u32_for(Format::Exf)?,
+        index: cursor.read_u16_for(Format::Exf)?,
+        version: cursor.read_u16_for(Format::Exf)?,
+        file_size: cursor.read_u32_for(Format::Exf)?,
+        seed: cursor.read_u32_for(Format::Exf)?,
+    };
+
+    if header.magic != EXF_MAGIC {
+        return Err(Error::malformed(Format::Exf, 0, "bad magic"));
+    }
+    check_index(header.index)?;
+
+    Ok(header)
+}
+
+pub fn decrypt_exf(buffer: &[u8]) -> Result<Vec<u8>> {
+    decrypt_exf_with_header(buffer).map(|(_, out_vec)| out_vec)
+}
+
+/// Decrypts `buffer` and also returns its header, whose `params()` re-encrypt it.
+/// EXF has no checksum, so damaged files decrypt to garbage instead of failing.
+pub fn decrypt_exf_with_header(buffer: &[u8]) -> Result<(ExfHeader, Vec<u8>)> {
+    let header = parse_exf_header(buffer)?;
+    let key = create_key(header.index, header.seed);
+
+    let data = get_slice(
+        buffer,
+        EXF_HEADER_SIZE,
+        header.file_size as usize,
+        Format::Exf,
+    )?;
+
+    let out_vec = data
+        .iter()
+        .enumerate()
+        .map(|(pos, &data)| {
+            let x = data ^ pos as u8;
+            let hi = x >> 4;
+            let lo = ((key[hi as usize] >> 4) ^ x) & 0xF;
+            let hi = (hi ^ key[pos & 0xF]) & 0xF;
+            (hi << 4) | lo
+        })
+        .collect();
+
+    Ok((header, out_vec))
+}
+
+pub fn encrypt_exf(buffer: &[u8]) -> Result<Vec<u8>> {
+    encrypt_exf_with(buffer, ExfParams::default())
+}
+
+pub fn encrypt_exf_with(buffer: &[u8], params: ExfParams) -> Result<Vec<u8>> {
+    check_index(params.index)?;
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(buffer.len() + EXF_HEADER_SIZE);
+
+    let file_size: u32 = buffer.len().try_into().map_err(|_| Error::TooLarge {
+        format: Format::Exf,
+        size: buffer.len(),
+    })?;
+    let seed = crc32fast::hash(buffer);
+
+    out_buf.write_u32::<LittleEndian>(EXF_MAGIC)?;
+    out_buf.write_u16::<LittleEndian>(params.index)?;
+    out_buf.write_u16::<LittleEndian>(params.version)?;
+    out_buf.write_u32::<LittleEndian>(file_size)?;
+    out_buf.write_u32::<LittleEndian>(seed)?;
+
+    let key = create_key(params.index, seed);
+
+    out_buf.extend(buffer.iter().enumerate().map(|(pos, &data)| {
+        let hi = ((data >> 4) ^ key[pos & 0xF]) & 0xF;
+        let lo = ((data & 0xF) ^ (key[hi as usize] >> 4)) & 0xF;
+        ((hi << 4) | lo) ^ pos as u8
+    }));
+
+    Ok(out_buf)
+}
+
+pub fn is_buf_exf(buffer: &[u8]) -> bool {
+    let magic = u32::from_le_bytes(
+        buffer
+            .get(0..4)
+            .unwrap_or_default()
+            .try_into()
+            .unwrap_or_default(),
+    );
+    magic == EXF_MAGIC
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        ecd::ECD_KEY_COUNT,
+        error::{Error, Format},
+    };
+
+    use super::{
+        ExfParams, decrypt_exf, decrypt_exf_with_header, encrypt_exf, encrypt_exf_with, is_buf_exf,
+    };
+
+    #[test]
+    fn exf_params_are_preserved() {
+        let data = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+
+        for index in 0..ECD_KEY_COUNT {
+            let params = ExfParams {
+                index,
+                version: 1234,
+            };
+            let encrypted = encrypt_exf_with(&data, params).unwrap();
+            assert!(is_buf_exf(&encrypted));
+            assert_ne!(&encrypted[16..], &data[..]);
+
+            let (header, decrypted) = decrypt_exf_with_header(&encrypted).unwrap();
+            assert_eq!(header.params(), params);
+            assert_eq!(header.file_size as usize, data.len());
+            assert_eq!(decrypted, data);
+            assert_eq!(
+                encrypt_exf_with(&decrypted, header.params()).unwrap(),
+                encrypted
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_exf_is_an_error() {
+        let params = ExfParams {
+            index: ECD_KEY_COUNT,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encrypt_exf_with(&[1, 2, 3], params),
+            Err(Error::InvalidKeyIndex { index: 6, .. })
+        ));
+
+        let encrypted = encrypt_exf(&[1, 2, 3]).unwrap();
+        assert!(decrypt_exf(&encrypted[..10]).is_err());
+        assert!(matches!(
+            decrypt_exf(&encrypted[..encrypted.len() - 1]),
+            Err(Error::Truncated {
+                format: Format::Exf,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored() {
+        let mut encrypted = encrypt_exf(&[1, 2, 3]).unwrap();
+        encrypted.extend_from_slice(&[0xAA; 4]);
+        assert_eq!(decrypt_exf(&encrypted).unwrap(), vec![1, 2, 3]);
+    }
+}
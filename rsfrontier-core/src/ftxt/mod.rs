@@ -0,0 +1,207 @@
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{
+    error::{Error, Format, ReadFormatExt, Result, get_slice},
+    text::{TextEntry, decode_sjis, encode_sjis},
+};
+
+const FTXT_MAGIC: u32 = 0x000B0000;
+const FTXT_HEADER_SIZE: usize = 16;
+
+/// A text file, laid out as:
+/// - 0x00 magic
+/// - 0x04 6 bytes of unknown use, kept as is
+/// - 0x0A u16 string count
+/// - 0x0C u32 size of the string table
+/// - 0x10 null terminated Shift-JIS strings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ftxt {
+    pub unknown: [u8; 6],
+    pub strings: Vec<String>,
+}
+
+impl Ftxt {
+    /// One entry per string, the id is the string's index in the file.
+    pub fn to_entries(&self) -> Vec<TextEntry> {
+        self.strings
+            .iter()
+            .enumerate()
+            .map(|(i, text)| TextEntry {
+                id: i.to_string(),
+                text: text.clone(),
+            })
+            .collect()
+    }
+
+    /// Replaces the strings whose index is listed in `entries`, the others are left untouched.
+    pub fn apply_entries(&mut self, entries: &[TextEntry]) -> Result<()> {
+        for entry in entries {
+            let index = self.parse_id(&entry.id)?;
+            self.strings[index] = entry.text.clone();
+        }
+        Ok(())
+    }
+
+    /// Builds a file from a full table, every id from 0 to the last string must be present once.
+    pub fn from_entries(entries: &[TextEntry]) -> Result<Self> {
+        let mut strings = vec![None; entries.len()];
+        for entry in entries {
+            let index = entry
+                .id
+                .parse::<usize>()
+                .ok()
+                .filter(|&i| i < strings.len())
+                .ok_or_else(|| Error::Text(format!("id '{}' is not a string index", entry.id)))?;
+            if strings[index].replace(entry.text.clone()).is_some() {
+                return Err(Error::Text(format!("id {} is listed twice", index)));
+            }
+        }
+
+        Ok(Ftxt {
+            unknown: [0; 6],
+            // No duplicates among as many ids as slots means every slot was filled
+            strings: strings.into_iter().flatten().collect(),
+        })
+    }
+
+    fn parse_id(&self, id: &str) -> Result<usize> {
+        id.parse::<usize>()
+            .ok()
+            .filter(|&i| i < self.strings.len())
+            .ok_or_else(|| {
+                Error::Text(format!(
+                    "id '{}' is not a string index, the file has {} strings",
+                    id,
+                    self.strings.len()
+                ))
+            })
+    }
+}
+
+pub fn decode_ftxt(buf: &[u8]) -> Result<Ftxt> {
+    let mut cursor = Cursor::new(buf);
+    if cursor.read_u32_for(Format::Ftxt)? != FTXT_MAGIC {
+        return Err(Error::malformed(Format::Ftxt, 0, "bad magic"));
+    }
+
+    let mut unknown = [0; 6];
+    for byte in &mut unknown {
+        *byte = cursor.read_u8_for(Format::Ftxt)?;
+    }
+    let string_count = cursor.read_u16_for(Format::Ftxt)?;
+    let table_size = cursor.read_u32_for(Format::Ftxt)?;
+    let table = get_slice(buf, FTXT_HEADER_SIZE, table_size as usize, Format::Ftxt)?;
+
+    let mut strings = Vec::with_capacity(string_count as usize);
+    let mut offset = 0;
+    for _ in 0..string_count {
+        let len = table[offset..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(Error::Truncated {
+                format: Format::Ftxt,
+                offset: FTXT_HEADER_SIZE + table.len(),
+            })?;
+        let text = decode_sjis(&table[offset..offset + len]).ok_or_else(|| {
+            Error::malformed(
+                Format::Ftxt,
+                FTXT_HEADER_SIZE + offset,
+                "invalid Shift-JIS string",
+            )
+        })?;
+        strings.push(text);
+        offset += len + 1;
+    }
+
+    Ok(Ftxt { unknown, strings })
+}
+
+pub fn encode_ftxt(ftxt: &Ftxt) -> Result<Vec<u8>> {
+    let string_count: u16 = ftxt.strings.len().try_into().map_err(|_| Error::TooLarge {
+        format: Format::Ftxt,
+        size: ftxt.strings.len(),
+    })?;
+
+    let mut table = Vec::new();
+    for (i, text) in ftxt.strings.iter().enumerate() {
+        let bytes = encode_sjis(text)
+            .ok_or_else(|| Error::Text(format!("string {} can't be encoded as Shift-JIS", i)))?;
+        if bytes.contains(&0) {
+            return Err(Error::Text(format!(
+                "string {} contains a null character",
+                i
+            )));
+        }
+        table.extend_from_slice(&bytes);
+        table.push(0);
+    }
+    let table_size: u32 = table.len().try_into().map_err(|_| Error::TooLarge {
+        format: Format::Ftxt,
+        size: table.len(),
+    })?;
+
+    let mut out_buf = Vec::with_capacity(FTXT_HEADER_SIZE + table.len());
+    out_buf.write_u32::<LittleEndian>(FTXT_MAGIC)?;
+    out_buf.extend_from_slice(&ftxt.unknown);
+    out_buf.write_u16::<LittleEndian>(string_count)?;
+    out_buf.write_u32::<LittleEndian>(table_size)?;
+    out_buf.extend_from_slice(&table);
+    Ok(out_buf)
+}
+
+pub fn is_buf_ftxt(buf: &[u8]) -> bool {
+    let magic = u32::from_le_bytes(
+        buf.get(0..4)
+            .unwrap_or_default()
+            .try_into()
+            .unwrap_or_default(),
+    );
+    magic == FTXT_MAGIC
+}
+
+#[cfg(test)]
+mod test {
+    use crate::text::{TableFormat, read_table, write_table};
+
+    use super::{Ftxt, decode_ftxt, encode_ftxt, is_buf_ftxt};
+
+    fn sample() -> Ftxt {
+        Ftxt {
+            unknown: [1, 2, 3, 4, 5, 6],
+            strings: vec![
+                "ハンターランク".to_string(),
+                "Line one\nLine two".to_string(),
+                String::new(),
+            ],
+        }
+    }
+
+    #[test]
+    fn ftxt_roundtrip() {
+        let ftxt = sample();
+        let buf = encode_ftxt(&ftxt).unwrap();
+        assert!(is_buf_ftxt(&buf));
+        assert_eq!(decode_ftxt(&buf).unwrap(), ftxt);
+
+        assert!(decode_ftxt(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn edited_table_is_reinserted() {
+        let ftxt = sample();
+        let table = write_table(&ftxt.to_entries(), TableFormat::Csv).unwrap();
+        let edited = table.replace("ハンターランク", "Hunter Rank");
+        let entries = read_table(&edited, TableFormat::Csv).unwrap();
+
+        let mut patched = ftxt.clone();
+        patched.apply_entries(&entries).unwrap();
+        assert_eq!(patched.strings[0], "Hunter Rank");
+        assert_eq!(patched.strings[1..], ftxt.strings[1..]);
+
+        let rebuilt = Ftxt::from_entries(&entries).unwrap();
+        assert_eq!(rebuilt.strings, patched.strings);
+        assert!(Ftxt::from_entries(&entries[1..]).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    Stripped, UnpackOptions,
+    entry::{Archive, StopAt, strip_layers_with},
+    error::{Error, Result},
+    magic::find_buf_extension,
+    run_with_jobs,
+    simple_archive::read_entry_table,
+};
+
+/// What a buffer is made of, found with the same detection as `unpack_buffer`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InspectNode {
+    /// Size of the node as stored, layers included.
+    pub size: usize,
+    /// Size once every layer is removed.
+    pub data_size: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<LayerInfo>,
+    #[serde(flatten)]
+    pub content: InspectContent,
+}
+
+/// A wrapping layer around a node, listed outermost first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerInfo {
+    Ecd {
+        index: u16,
+        version: u16,
+        crc32: u32,
+        /// Whether the decrypted payload matches `crc32`.
+        crc_ok: bool,
+    },
+    Exf {
+        index: u16,
+        version: u16,
+    },
+    Jpk {
+        jpk_type: u16,
+        packed_size: usize,
+        unpacked_size: usize,
+        /// `packed_size` over `unpacked_size`.
+        ratio: f32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InspectContent {
+    /// A leaf, `extension` is the one `unpack` gives it.
+    File { extension: String },
+    SimpleArchive {
+        /// Entry alignment, unset when no alignment explains the offsets.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        alignment: Option<u32>,
+        entries: Vec<SimpleEntryInfo>,
+    },
+    Mha {
+        base_id: u16,
+        capacity: u16,
+        entries: Vec<MhaEntryInfo>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimpleEntryInfo {
+    pub offset: u32,
+    #[serde(flatten)]
+    pub node: InspectNode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MhaEntryInfo {
+    pub name: String,
+    pub id: u32,
+    pub padded_size: u32,
+    #[serde(flatten)]
+    pub node: InspectNode,
+}
+
+impl InspectNode {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+}
+
+/// Describes every layer and archive entry of `buf` without writing anything.
+/// `prefix_path` is the path `unpack_buffer_with_options` would be given, it is only used to
+/// match `options.simple_archive_overrides` and in errors.
+pub fn inspect_buffer(
+    prefix_path: &str,
+    buf: &[u8],
+    options: &UnpackOptions,
+) -> Result<InspectNode> {
+    let path = PathBuf::from(prefix_path);
+    let root = path.parent().unwrap_or(Path::new(""));
+    run_with_jobs(options.jobs, || inspect_node(buf, &path, root, options))
+}
+
+fn inspect_node(
+    buf: &[u8],
+    path: &Path,
+    root: &Path,
+    options: &UnpackOptions,
+) -> Result<InspectNode> {
+    let mut layers = Vec::new();
+    let (_, processed_buffer) =
+        strip_layers_with(buf.to_vec(), path, StopAt::Data, |stripped, packed| {
+            layers.push(match stripped {
+                Stripped::Ecd(header, decrypted) => LayerInfo::Ecd {
+                    index: header.index,
+                    version: header.version,
+                    crc32: header.crc32,
+                    crc_ok: header.check_crc(decrypted).is_ok(),
+                },
+                Stripped::Exf(header, _) => LayerInfo::Exf {
+                    index: header.index,
+                    version: header.version,
+                },
+                Stripped::Jpk(jpk_type, decoded) => LayerInfo::Jpk {
+                    jpk_type: *jpk_type,
+                    packed_size: packed.len(),
+                    unpacked_size: decoded.len(),
+                    ratio: packed.len() as f32 / decoded.len().max(1) as f32,
+                },
+            });
+            Ok(())
+        })?;
+
+    let archive =
+        Archive::detect(&processed_buffer, path, root, options).map_err(|e| e.in_layer(path))?;
+    let nodes = archive
+        .as_ref()
+        .map(|archive| {
+            archive
+                .entry_names()
+                .into_par_iter()
+                .enumerate()
+                .map(|(i, (folder, _))| {
+                    let new_path = path.join(folder);
+                    inspect_node(archive.entry(i), &new_path, root, options)
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let content = match archive {
+        Some(Archive::Simple { layout, .. }) => {
+            let table = read_entry_table(&processed_buffer).map_err(|e| e.in_layer(path))?;
+            InspectContent::SimpleArchive {
+                alignment: layout.offsets.is_none().then_some(layout.alignment),
+                entries: table
+                    .into_iter()
+                    .zip(nodes)
+                    .map(|((offset, _), node)| SimpleEntryInfo {
+                        offset: offset as u32,
+                        node,
+                    })
+                    .collect(),
+            }
+        }
+        Some(Archive::Mha {
+            base_id,
+            capacity,
+            entries,
+        }) => InspectContent::Mha {
+            base_id,
+            capacity,
+            entries: entries
+                .into_iter()
+                .zip(nodes)
+                .map(|(entry, node)| MhaEntryInfo {
+                    name: entry.name,
+                    id: entry.id,
+                    padded_size: entry.padded_size,
+                    node,
+                })
+                .collect(),
+        },
+        None => InspectContent::File {
+            extension: find_buf_extension(&processed_buffer).to_string(),
+        },
+    };
+
+    Ok(InspectNode {
+        size: buf.len(),
+        data_size: processed_buffer.len(),
+        layers,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        UnpackOptions, ecd,
+        jpk::create_jpk,
+        mha::{MhaEntry, encode_mha_archive},
+        simple_archive::encode_simple_archive_aligned,
+    };
+
+    use super::{InspectContent, LayerInfo, inspect_buffer};
+
+    #[test]
+    fn nested_layers_are_described() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let compressed = create_jpk(&uncomp, 3).unwrap();
+        let mha =
+            encode_mha_archive(&[MhaEntry::new("a.bin".to_string(), 3, vec![7; 8])], 3, 8).unwrap();
+        let archive = encode_simple_archive_aligned(&[compressed.clone(), mha], 1).unwrap();
+        let encrypted = ecd::encrypt_ecd(&archive).unwrap();
+
+        let node = inspect_buffer("quest", &encrypted, &UnpackOptions::default()).unwrap();
+        assert_eq!(node.size, encrypted.len());
+        assert_eq!(node.data_size, archive.len());
+        assert!(matches!(
+            node.layers[..],
+            [LayerInfo::Ecd {
+                index: 4,
+                crc_ok: true,
+                ..
+            }]
+        ));
+
+        let InspectContent::SimpleArchive { alignment, entries } = &node.content else {
+            panic!("not a simple archive: {:?}", node.content);
+        };
+        assert_eq!(*alignment, Some(1));
+        assert_eq!(entries[0].offset, 20);
+        assert_eq!(
+            entries[0].node.layers,
+            vec![LayerInfo::Jpk {
+                jpk_type: 3,
+                packed_size: compressed.len(),
+                unpacked_size: uncomp.len(),
+                ratio: compressed.len() as f32 / uncomp.len() as f32,
+            }]
+        );
+        assert!(matches!(
+            &entries[1].node.content,
+            InspectContent::Mha { base_id: 3, entries, .. } if entries[0].name == "a.bin" && entries[0].id == 3
+        ));
+
+        let json = node.to_json().unwrap();
+        assert!(json.contains("\"kind\": \"simple_archive\""));
+        assert!(json.contains("\"crc_ok\": true"));
+    }
+
+    #[test]
+    fn crc_mismatch_is_reported() {
+        let mut encrypted = ecd::encrypt_ecd(&[1, 2, 3, 4]).unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0xFF;
+
+        let node = inspect_buffer("file", &encrypted, &UnpackOptions::default()).unwrap();
+        assert!(matches!(
+            node.layers[..],
+            [LayerInfo::Ecd { crc_ok: false, .. }]
+        ));
+    }
+}
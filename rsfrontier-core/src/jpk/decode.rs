@@ -1,75 +1,169 @@
-use std::io::{self, Cursor, Seek};
+use std::io::Cursor;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use crate::error::{Error, Format, ReadFormatExt, Result, get_slice};
 
-struct HuffmanState {
-    flag: u8,
-    flag_shift: i8,
-    table_length: u16,
+// Bits of a huffman code resolved with a single table lookup
+const HUFF_LOOKUP_BITS: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+enum HuffLookup {
+    /// The code of `byte` is `len` bits long.
+    Leaf { byte: u8, len: u8 },
+    /// The code is longer than `HUFF_LOOKUP_BITS`, this is the node reached after them.
+    Node(u16),
+    /// The code leads outside the buffer, decoding stops there.
+    Invalid,
+}
+
+/// The serialized JPK tree: values under 0x100 are leaves, any other value `v` is an
+/// internal node whose children are the u16 at index `(v - 0x100) * 2 + bit` of the table.
+struct HuffmanTree<'a> {
+    buf: &'a [u8],
     table_offset: usize,
-    data_offset: usize,
+    root: u16,
+}
+
+impl HuffmanTree<'_> {
+    fn child(&self, node: u16, bit: usize) -> Option<u16> {
+        let offset = ((node as usize - 0x100) * 2 + bit) * 2 + self.table_offset;
+        let bytes = self.buf.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Decodes every `HUFF_LOOKUP_BITS` bit pattern from the root.
+    fn build_lookup(&self) -> Vec<HuffLookup> {
+        (0..1usize << HUFF_LOOKUP_BITS)
+            .map(|pattern| {
+                let mut node = self.root;
+                for depth in 0..HUFF_LOOKUP_BITS {
+                    let bit = (pattern >> (HUFF_LOOKUP_BITS - 1 - depth)) & 1;
+                    match self.child(node, bit) {
+                        None => return HuffLookup::Invalid,
+                        Some(value) if value < 0x100 => {
+                            return HuffLookup::Leaf {
+                                byte: value as u8,
+                                len: depth as u8 + 1,
+                            };
+                        }
+                        Some(value) => node = value,
+                    }
+                }
+                HuffLookup::Node(node)
+            })
+            .collect()
+    }
+}
+
+/// Reads a stream most significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    /// The next `count` (at most 16) bits, zero filled past the end of the data.
+    fn peek(&self, count: usize) -> usize {
+        let index = self.pos / 8;
+        let window = match self.data.get(index..index + 3) {
+            Some(bytes) => u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]),
+            None => (0..3).fold(0, |acc, i| {
+                (acc << 8) | *self.data.get(index + i).unwrap_or(&0) as u32
+            }),
+        };
+        ((window << (8 + self.pos % 8)) >> (32 - count)) as usize
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.pos += count;
+    }
 }
 
-pub fn decode_jpk_raw(buf: &[u8], size: usize) -> Vec<u8> {
-    buf[..size].to_vec()
+pub fn decode_jpk_raw(buf: &[u8], size: usize) -> Result<Vec<u8>> {
+    Ok(get_slice(buf, 0, size, Format::Jpk)?.to_vec())
 }
 
-fn consume_bit(cursor: &mut Cursor<&[u8]>, shift_idx: &mut i8, flag: &mut u8) -> u8 {
+fn consume_bit(cursor: &mut Cursor<&[u8]>, shift_idx: &mut i8, flag: &mut u8) -> Result<u8> {
     *shift_idx -= 1;
 
     if *shift_idx < 0 {
         *shift_idx = 7;
-        *flag = cursor.read_u8().unwrap();
+        *flag = cursor.read_u8_for(Format::Jpk)?;
     }
 
-    (*flag >> *shift_idx) & 1
+    Ok((*flag >> *shift_idx) & 1)
 }
 
-fn backref_to_out(out: &mut Vec<u8>, offset: usize, length: usize, out_index: &mut usize) {
+fn backref_to_out(
+    out: &mut Vec<u8>,
+    offset: usize,
+    length: usize,
+    out_index: &mut usize,
+    cursor: &Cursor<&[u8]>,
+) -> Result<()> {
+    if offset >= *out_index {
+        return Err(Error::malformed(
+            Format::Jpk,
+            cursor.position() as usize,
+            format!(
+                "back-reference to {} bytes back with only {} bytes decoded",
+                offset + 1,
+                out_index
+            ),
+        ));
+    }
+
     for _ in 0..length {
         let byte = out[*out_index - offset - 1];
         out.push(byte);
         *out_index += 1;
     }
+
+    Ok(())
 }
 
-pub fn decode_jpk_lz(buf: &[u8], size: usize) -> Vec<u8> {
+pub fn decode_jpk_lz(buf: &[u8], size: usize) -> Result<Vec<u8>> {
     let mut cursor = Cursor::new(buf);
     let mut flag: u8 = 0;
     let mut shift_idx: i8 = -1;
     let mut out_index: usize = 0;
-    let mut out: Vec<u8> = Vec::with_capacity(size);
+    // `size` comes from the header, a bad one mustn't reserve gigabytes up front
+    let mut out: Vec<u8> = Vec::with_capacity(size.min(buf.len() * 8 + 1024));
 
     while out.len() < size {
         //Get a bit from the control flag
-        let bit = consume_bit(&mut cursor, &mut shift_idx, &mut flag);
+        let bit = consume_bit(&mut cursor, &mut shift_idx, &mut flag)?;
 
         //bit is 0 then we copy the byte to out_buf
         if bit == 0 {
-            let byte = cursor.read_u8().unwrap();
+            let byte = cursor.read_u8_for(Format::Jpk)?;
             out.push(byte);
             out_index += 1;
         }
 
         //We have a backref
         if bit == 1 {
-            let backref_type = consume_bit(&mut cursor, &mut shift_idx, &mut flag);
+            let backref_type = consume_bit(&mut cursor, &mut shift_idx, &mut flag)?;
             if backref_type == 0 {
                 //we're in a short ref
-                let length = (consume_bit(&mut cursor, &mut shift_idx, &mut flag) << 1)
-                    | consume_bit(&mut cursor, &mut shift_idx, &mut flag);
-                let offset = cursor.read_u8().unwrap();
+                let length = (consume_bit(&mut cursor, &mut shift_idx, &mut flag)? << 1)
+                    | consume_bit(&mut cursor, &mut shift_idx, &mut flag)?;
+                let offset = cursor.read_u8_for(Format::Jpk)?;
                 backref_to_out(
                     &mut out,
                     offset as usize,
                     (length + 3) as usize,
                     &mut out_index,
-                );
+                    &cursor,
+                )?;
             }
             if backref_type == 1 {
                 //We're in a long ref
-                let high_byte = cursor.read_u8().unwrap();
-                let low_byte = cursor.read_u8().unwrap();
+                let high_byte = cursor.read_u8_for(Format::Jpk)?;
+                let low_byte = cursor.read_u8_for(Format::Jpk)?;
                 let length = (high_byte & 0xE0) >> 5;
                 let offset: u16 = (((high_byte & 0x1F) as u16) << 8) | low_byte as u16;
 
@@ -80,28 +174,30 @@ pub fn decode_jpk_lz(buf: &[u8], size: usize) -> Vec<u8> {
                         offset as usize,
                         (length + 2) as usize,
                         &mut out_index,
-                    );
+                        &cursor,
+                    )?;
                 }
                 //Special cases
                 if length == 0 {
-                    let special_case_bit = consume_bit(&mut cursor, &mut shift_idx, &mut flag);
+                    let special_case_bit = consume_bit(&mut cursor, &mut shift_idx, &mut flag)?;
                     if special_case_bit == 0 {
-                        let length = consume_bit(&mut cursor, &mut shift_idx, &mut flag) << 3
-                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag) << 2
-                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag) << 1
-                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag);
+                        let length = consume_bit(&mut cursor, &mut shift_idx, &mut flag)? << 3
+                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag)? << 2
+                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag)? << 1
+                            | consume_bit(&mut cursor, &mut shift_idx, &mut flag)?;
                         backref_to_out(
                             &mut out,
                             offset as usize,
                             (length + 2 + 8) as usize,
                             &mut out_index,
-                        );
+                            &cursor,
+                        )?;
                     }
                     if special_case_bit == 1 {
-                        let temp = cursor.read_u8().unwrap();
+                        let temp = cursor.read_u8_for(Format::Jpk)?;
                         if temp == 0xFF {
                             for _ in 0..offset + 0x1B {
-                                out.push(cursor.read_u8().unwrap());
+                                out.push(cursor.read_u8_for(Format::Jpk)?);
                                 out_index += 1;
                             }
                         }
@@ -111,7 +207,8 @@ pub fn decode_jpk_lz(buf: &[u8], size: usize) -> Vec<u8> {
                                 offset as usize,
                                 temp as usize + 0x1A,
                                 &mut out_index,
-                            );
+                                &cursor,
+                            )?;
                         }
                     }
                 }
@@ -119,73 +216,161 @@ pub fn decode_jpk_lz(buf: &[u8], size: usize) -> Vec<u8> {
         }
     }
 
-    out
+    Ok(out)
 }
 
-fn read_huff_byte(cursor: &mut Cursor<&[u8]>, hf: &mut HuffmanState) -> io::Result<u8> {
-    let mut data = hf.table_length;
-
-    while data >= 0x100 {
-        hf.flag_shift -= 1;
-        if hf.flag_shift < 0 {
-            hf.flag_shift = 7;
-            cursor.seek(std::io::SeekFrom::Start(hf.data_offset as u64))?;
-            hf.data_offset += 1;
-            hf.flag = cursor.read_u8()?;
-        }
-        let bit = (hf.flag >> hf.flag_shift) & 1;
-        let offset = ((data as u64) * 2 - 0x200 + bit as u64) * 2 + hf.table_offset as u64;
-        cursor.seek(std::io::SeekFrom::Start(offset))?;
-        data = cursor.read_u16::<LittleEndian>()?;
-    }
-
-    Ok(data as u8)
+pub fn decode_jpk_huff_lz(buf: &[u8], size: usize) -> Result<Vec<u8>> {
+    let out_vec = decode_jpk_huff(buf)?;
+    // Offsets inside the LZ stream refer to the huffman decoded buffer, not to `buf`
+    decode_jpk_lz(&out_vec, size).map_err(|e| match e {
+        Error::Truncated { .. } | Error::Malformed { .. } => Error::malformed(
+            Format::Jpk,
+            0,
+            format!("invalid LZ stream after huffman: {}", e),
+        ),
+        _ => e,
+    })
 }
 
-fn initialize_huffman_state(
-    table_length: u16,
-    table_offset: usize,
-    data_offset: usize,
-) -> HuffmanState {
-    HuffmanState {
-        flag: 0,
-        flag_shift: -1,
-        table_length,
-        table_offset,
-        data_offset,
-    }
-}
-
-pub fn decode_jpk_huff_lz(buf: &[u8], size: usize) -> Vec<u8> {
-    let out_vec = decode_jpk_huff(buf);
-    decode_jpk_lz(&out_vec, size)
-}
-
-pub fn decode_jpk_huff(buf: &[u8]) -> Vec<u8> {
+/// Decodes huffman coded bytes until the bit stream runs out, trailing padding bits
+/// can produce extra bytes past the expected size.
+pub fn decode_jpk_huff(buf: &[u8]) -> Result<Vec<u8>> {
     let mut cursor = Cursor::new(buf);
 
-    let table_length = cursor.read_u16::<LittleEndian>().unwrap();
+    let table_length = cursor.read_u16_for(Format::Jpk)?;
     let table_offset = cursor.position() as usize;
-    let data_offset = table_offset + table_length as usize * 4 - 0x3fc;
-
-    let mut hf_state = initialize_huffman_state(table_length, table_offset, data_offset);
-
-    let mut out_vec: Vec<u8> = Vec::new();
+    let data_offset = (table_offset + table_length as usize * 4)
+        .checked_sub(0x3fc)
+        .ok_or_else(|| {
+            Error::malformed(
+                Format::Jpk,
+                0,
+                format!("huffman table length {} is too small", table_length),
+            )
+        })?;
+    if table_length < 0x100 {
+        return Err(Error::malformed(
+            Format::Jpk,
+            0,
+            "huffman tree has no internal node",
+        ));
+    }
 
-    while let Ok(byte) = read_huff_byte(&mut cursor, &mut hf_state) {
+    let tree = HuffmanTree {
+        buf,
+        table_offset,
+        root: table_length,
+    };
+    let lookup = tree.build_lookup();
+    let mut bits = BitReader {
+        data: buf.get(data_offset..).unwrap_or_default(),
+        pos: 0,
+    };
+
+    let mut out_vec: Vec<u8> = Vec::with_capacity(bits.remaining() / 4);
+    'symbols: loop {
+        let byte = match lookup[bits.peek(HUFF_LOOKUP_BITS)] {
+            HuffLookup::Leaf { byte, len } => {
+                if len as usize > bits.remaining() {
+                    break;
+                }
+                bits.skip(len as usize);
+                byte
+            }
+            HuffLookup::Node(mut node) => {
+                if bits.remaining() < HUFF_LOOKUP_BITS {
+                    break;
+                }
+                bits.skip(HUFF_LOOKUP_BITS);
+                loop {
+                    if bits.remaining() == 0 {
+                        break 'symbols;
+                    }
+                    let bit = bits.peek(1);
+                    bits.skip(1);
+                    match tree.child(node, bit) {
+                        None => break 'symbols,
+                        Some(value) if value < 0x100 => break value as u8,
+                        Some(value) => node = value,
+                    }
+                }
+            }
+            HuffLookup::Invalid => break,
+        };
         out_vec.push(byte);
     }
 
-    out_vec
+    Ok(out_vec)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, io::Cursor};
+    use std::{
+        fs,
+        io::{self, Cursor, Seek, SeekFrom},
+        time::Instant,
+    };
+
+    use byteorder::{LittleEndian, ReadBytesExt};
+
+    use crate::jpk::{decode::consume_bit, encode::encode_jpk_huff};
+
+    use super::{decode_jpk_huff, decode_jpk_huff_lz, decode_jpk_lz};
+
+    /// The previous decoder, walking the serialized tree one bit at a time.
+    fn decode_jpk_huff_bitwise(buf: &[u8]) -> Vec<u8> {
+        fn read_huff_byte(
+            cursor: &mut Cursor<&[u8]>,
+            table_length: u16,
+            flag: &mut u8,
+            flag_shift: &mut i8,
+            data_offset: &mut usize,
+        ) -> io::Result<u8> {
+            let mut data = table_length;
+            while data >= 0x100 {
+                *flag_shift -= 1;
+                if *flag_shift < 0 {
+                    *flag_shift = 7;
+                    cursor.seek(SeekFrom::Start(*data_offset as u64))?;
+                    *data_offset += 1;
+                    *flag = cursor.read_u8()?;
+                }
+                let bit = (*flag >> *flag_shift) & 1;
+                let offset = ((data as u64) * 2 - 0x200 + bit as u64) * 2 + 2;
+                cursor.seek(SeekFrom::Start(offset))?;
+                data = cursor.read_u16::<LittleEndian>()?;
+            }
+            Ok(data as u8)
+        }
 
-    use crate::jpk::decode::consume_bit;
+        let mut cursor = Cursor::new(buf);
+        let table_length = cursor.read_u16::<LittleEndian>().unwrap();
+        let mut data_offset = 2 + table_length as usize * 4 - 0x3fc;
+        let (mut flag, mut flag_shift) = (0, -1);
+        let mut out = Vec::new();
+        while let Ok(byte) = read_huff_byte(
+            &mut cursor,
+            table_length,
+            &mut flag,
+            &mut flag_shift,
+            &mut data_offset,
+        ) {
+            out.push(byte);
+        }
+        out
+    }
 
-    use super::{decode_jpk_huff_lz, decode_jpk_lz};
+    /// Skewed pseudo random bytes, so codes of many lengths are used.
+    fn huff_sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let r = (state >> 16) as u8;
+                r & (r >> 1) & (r >> 2) | (r >> 5)
+            })
+            .collect()
+    }
 
     #[test]
     fn consume_bit_test() {
@@ -193,16 +378,43 @@ mod tests {
         let mut flag: u8 = 0b00100011;
         let mut shift_idx: i8 = -1;
 
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 0);
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            0
+        );
         assert_eq!(shift_idx, 7);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 0);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 1);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 0);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 0);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 0);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 1);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 1);
-        assert_eq!(consume_bit(&mut cursor, &mut shift_idx, &mut flag), 1);
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            0
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            1
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            0
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            0
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            0
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            1
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            1
+        );
+        assert_eq!(
+            consume_bit(&mut cursor, &mut shift_idx, &mut flag).unwrap(),
+            1
+        );
         assert_eq!(shift_idx, 7);
         assert_eq!(flag, 0xCB);
     }
@@ -214,7 +426,7 @@ mod tests {
         let expected_size = 14640;
         let start_off = 16;
 
-        let out = decode_jpk_lz(&compressed_data[start_off..], expected_size);
+        let out = decode_jpk_lz(&compressed_data[start_off..], expected_size).unwrap();
 
         assert_eq!(out, decompressed_data);
     }
@@ -224,7 +436,7 @@ mod tests {
         let decompressed_data = fs::read("./tests/data/mhfdat_decrypt_decomp.bin").unwrap();
         let compressed_data = fs::read("./tests/data/mhfdat_decrypted_only.bin").unwrap();
 
-        let out = decode_jpk_huff_lz(&compressed_data[16..], 28722096);
+        let out = decode_jpk_huff_lz(&compressed_data[16..], 28722096).unwrap();
 
         assert!(out == decompressed_data, "The buffers are not equal");
     }
@@ -233,11 +445,64 @@ mod tests {
     fn huff_rw_decomp() {
         let decompressed_data = fs::read("./tests/data/mhfdat_decrypt_decomp.bin").unwrap();
         let compressed_data = fs::read("./tests/data/mhfdat_decrypted_only.bin").unwrap();
-        let decompressed_lz = decode_jpk_huff_lz(&compressed_data[16..], 28722096);
+        let decompressed_lz = decode_jpk_huff_lz(&compressed_data[16..], 28722096).unwrap();
 
         assert!(
             decompressed_data == decompressed_lz,
             "the buffer are not equal"
         );
     }
+
+    #[test]
+    fn backref_before_start_is_an_error() {
+        // flag bits 1, 0 start a short back-reference, which points before the first byte
+        let data: &[u8] = &[0b1000_0000, 0x05];
+        assert!(decode_jpk_lz(data, 4).is_err());
+    }
+
+    #[test]
+    fn huff_table_matches_bitwise_decoder() {
+        let inputs = [
+            vec![7, 7, 8],
+            b"aaaaaaaaab".to_vec(),
+            (0..=255).collect(),
+            huff_sample(5000),
+        ];
+        for input in inputs {
+            let encoded = encode_jpk_huff(&input);
+            let decoded = decode_jpk_huff(&encoded).unwrap();
+            assert_eq!(decoded, decode_jpk_huff_bitwise(&encoded));
+            assert_eq!(decoded[..input.len()], input);
+
+            // Cut streams stop at the same byte
+            let truncated = &encoded[..encoded.len() - 1];
+            assert_eq!(
+                decode_jpk_huff(truncated).unwrap(),
+                decode_jpk_huff_bitwise(truncated)
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "slow, compares decoding speed, run with --release --ignored"]
+    fn huff_decode_benchmark() {
+        let input = huff_sample(1 << 20);
+        let encoded = encode_jpk_huff(&input);
+
+        let start = Instant::now();
+        let bitwise = decode_jpk_huff_bitwise(&encoded);
+        let bitwise_time = start.elapsed();
+
+        let start = Instant::now();
+        let table = decode_jpk_huff(&encoded).unwrap();
+        let table_time = start.elapsed();
+
+        assert_eq!(table, bitwise);
+        assert!(
+            table_time < bitwise_time,
+            "table {:?}, bitwise {:?}",
+            table_time,
+            bitwise_time
+        );
+    }
 }
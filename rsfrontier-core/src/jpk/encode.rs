@@ -1,8 +1,10 @@
-use std::{cmp::Reverse, collections::HashMap, hash::Hash};
+use std::{cmp::Reverse, collections::VecDeque, hash::Hash};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use priority_queue::PriorityQueue;
 
+use super::CompressionLevel;
+
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum HuffmanNode {
     Leaf {
@@ -64,213 +66,454 @@ impl<'a> BitWriter<'a> {
     }
 }
 
-pub fn find_longest_match(
-    in_buffer: &[u8],
-    hash_chain: &HashMap<u32, usize>,
-    current_buffer: &[u8],
-    min_length: usize,
-) -> Option<(usize, usize)> {
-    let mut best_match: Option<(usize, usize)> = None;
-    if (current_buffer.len() < min_length) || (in_buffer.len() < min_length) {
-        return None;
+// Largest back-reference distance, the offset field has 13 bits
+const WINDOW_SIZE: usize = 8192;
+const MIN_MATCH: usize = 3;
+// Longest length of the extended long back-reference, whose length byte 0xFF is reserved
+const MAX_MATCH: usize = 0xFE + 0x1A;
+// Short back-references have an 8 bit offset and a 2 bit length
+const SHORT_MAX_DISTANCE: usize = 256;
+const SHORT_MAX_LENGTH: usize = 6;
+const HASH_BITS: u32 = 15;
+// The long back-reference escape with a 0xFF length byte copies `offset + 0x1B` raw bytes
+const MIN_LITERAL_RUN: usize = 0x1B;
+const MAX_LITERAL_RUN: usize = 0x1FFF + 0x1B;
+
+// Size of every token in bits, flag bits included
+const LITERAL_BITS: u32 = 9;
+const SHORT_REF_BITS: u32 = 2 + 2 + 8;
+const LONG_REF_BITS: u32 = 2 + 16;
+const NIBBLE_LENGTH_REF_BITS: u32 = LONG_REF_BITS + 1 + 4;
+const BYTE_LENGTH_REF_BITS: u32 = LONG_REF_BITS + 1 + 8;
+// Plus 8 bits per copied byte
+const LITERAL_RUN_BITS: u32 = LONG_REF_BITS + 1 + 8;
+
+// Optimal parsing works on blocks of this many bytes, bounding the size of its tables
+const OPTIMAL_BLOCK_SIZE: usize = 1 << 16;
+// Matches this long are taken as is by the optimal parser, the positions they cover aren't searched
+const OPTIMAL_NICE_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Match {
+    distance: usize,
+    length: usize,
+}
+
+impl Match {
+    fn bits(self) -> u32 {
+        if self.distance <= SHORT_MAX_DISTANCE && self.length <= SHORT_MAX_LENGTH {
+            SHORT_REF_BITS
+        } else if self.length <= 9 {
+            LONG_REF_BITS
+        } else if self.length <= 25 {
+            NIBBLE_LENGTH_REF_BITS
+        } else {
+            BYTE_LENGTH_REF_BITS
+        }
     }
-    let pattern = &current_buffer[0..min_length];
-    let pattern_hash = calculate_hash(pattern);
+}
 
-    if let Some(i) = hash_chain.get(&pattern_hash) {
-        let in_buffer_rest = &in_buffer[*i + min_length..];
-        let cur_buf_rest = &current_buffer[min_length..];
+/// How the optimal parser reached a position.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Literal,
+    Match(Match),
+    LiteralRun(usize),
+}
 
-        let extended_length = in_buffer_rest
-            .iter()
-            .zip(cur_buf_rest)
-            .take_while(|(a, b)| *a == *b)
-            .count();
+/// Search effort of a `CompressionLevel`.
+struct MatchParams {
+    /// Most candidates tried per position.
+    max_chain: usize,
+    /// Matches at least this long are taken without looking further.
+    nice_length: usize,
+    /// Check whether the next position has a longer match before taking one.
+    lazy: bool,
+}
 
-        let total_length = min_length + extended_length;
-        //We found a good enough match returning this one
-        if total_length >= 32 {
-            best_match = Some((*i, total_length));
-            return best_match;
+impl CompressionLevel {
+    fn match_params(self) -> MatchParams {
+        match self {
+            CompressionLevel::Fast => MatchParams {
+                max_chain: 4,
+                nice_length: 32,
+                lazy: false,
+            },
+            CompressionLevel::Default => MatchParams {
+                max_chain: 64,
+                nice_length: 128,
+                lazy: true,
+            },
+            CompressionLevel::Max => MatchParams {
+                max_chain: WINDOW_SIZE,
+                nice_length: MAX_MATCH,
+                lazy: true,
+            },
+            CompressionLevel::Optimal => MatchParams {
+                max_chain: 1024,
+                nice_length: OPTIMAL_NICE_LENGTH,
+                lazy: false,
+            },
         }
+    }
+}
+
+/// Hash chains over the last `WINDOW_SIZE` positions, indexed by their first 3 bytes.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    /// Most recent position + 1 for each hash, 0 when there is none.
+    head: Vec<u32>,
+    /// Previous position + 1 with the same hash, for each position in the window.
+    prev: Vec<u32>,
+    max_chain: usize,
+    nice_length: usize,
+}
 
-        if best_match.is_none_or(|(_, len)| total_length > len) {
-            best_match = Some((*i, total_length));
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8], params: &MatchParams) -> Self {
+        MatchFinder {
+            data,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+            max_chain: params.max_chain,
+            nice_length: params.nice_length,
         }
     }
 
-    best_match
-}
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.data[pos..pos + MIN_MATCH];
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+        (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
 
-fn set_bit_flag(out_buffer: &mut Vec<u8>, flag_idx: &mut usize, shift_idx: &mut i8, bit_val: u8) {
-    *shift_idx -= 1;
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = pos as u32 + 1;
+    }
 
-    if *shift_idx < 0 {
-        *shift_idx = 7;
-        out_buffer.push(0);
-        *flag_idx = out_buffer.len() - 1;
+    /// Longest match for `pos`, the closest one among equally long matches.
+    /// Must be called before `pos` is inserted.
+    fn find(&self, pos: usize) -> Option<Match> {
+        let mut best = None;
+        self.search(pos, self.data.len(), |m| best = Some(m));
+        best
     }
 
-    if bit_val == 1 {
-        out_buffer[*flag_idx] |= 1 << *shift_idx;
+    /// Calls `found` with each match for `pos` that is longer than the previous ones, nearest
+    /// first, so every length up to the last one is best reached with the first match at
+    /// least that long. Matches end before `end`.
+    fn search(&self, pos: usize, end: usize, mut found: impl FnMut(Match)) {
+        let max_length = MAX_MATCH.min(end - pos);
+        if max_length < MIN_MATCH {
+            return;
+        }
+
+        let current = &self.data[pos..pos + max_length];
+        let mut best_length = MIN_MATCH - 1;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..self.max_chain {
+            let Some(candidate_pos) = (candidate as usize).checked_sub(1) else {
+                break;
+            };
+            let distance = pos - candidate_pos;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+
+            // The byte after the best length has to match for this candidate to be longer
+            if self.data[candidate_pos + best_length] == current[best_length] {
+                let length = self.data[candidate_pos..]
+                    .iter()
+                    .zip(current)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    found(Match { distance, length });
+                    if length >= self.nice_length || length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            // Chains only go back in time, a newer link means the slot was reused
+            let next = self.prev[candidate_pos % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
     }
 }
 
-pub fn calculate_hash(buf: &[u8]) -> u32 {
-    if buf.len() < 3 {
-        return 0;
+/// Writes LZ tokens, interleaving flag bytes with the data bytes that follow them.
+struct LzWriter {
+    out: Vec<u8>,
+    flag_idx: usize,
+    shift_idx: i8,
+}
+
+impl LzWriter {
+    fn new() -> Self {
+        LzWriter {
+            out: Vec::new(),
+            flag_idx: 0,
+            shift_idx: 0,
+        }
     }
 
-    u32::from_ne_bytes([buf[0], buf[1], buf[2], 0])
+    fn bit(&mut self, bit: u8) {
+        self.shift_idx -= 1;
+        if self.shift_idx < 0 {
+            self.shift_idx = 7;
+            self.out.push(0);
+            self.flag_idx = self.out.len() - 1;
+        }
+
+        if bit == 1 {
+            self.out[self.flag_idx] |= 1 << self.shift_idx;
+        }
+    }
+
+    fn bits(&mut self, value: usize, count: usize) {
+        for i in (0..count).rev() {
+            self.bit(((value >> i) & 1) as u8);
+        }
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.bit(0);
+        self.out.push(byte);
+    }
+
+    /// Stores `bytes` as is behind a single escape, `MIN_LITERAL_RUN` to `MAX_LITERAL_RUN` bytes.
+    fn literal_run(&mut self, bytes: &[u8]) {
+        let offset = bytes.len() - MIN_LITERAL_RUN;
+        self.bit(1);
+        self.bit(1);
+        self.out.push((offset >> 8) as u8);
+        self.out.push(offset as u8);
+        self.bit(1);
+        self.out.push(0xFF);
+        self.out.extend_from_slice(bytes);
+    }
+
+    /// Stores `bytes` in literal runs when that is smaller than one literal per byte.
+    fn literals(&mut self, bytes: &[u8]) {
+        let mut rest = bytes;
+        // A run costs 27 bits more than its bytes, one literal 1 bit more than its byte
+        while rest.len() > MIN_LITERAL_RUN {
+            let (run, tail) = rest.split_at(rest.len().min(MAX_LITERAL_RUN));
+            self.literal_run(run);
+            rest = tail;
+        }
+        for &byte in rest {
+            self.literal(byte);
+        }
+    }
+
+    fn back_ref(&mut self, m: Match) {
+        let offset = m.distance - 1;
+        let length = m.length;
+        self.bit(1);
+
+        if m.distance <= SHORT_MAX_DISTANCE && length <= SHORT_MAX_LENGTH {
+            self.bit(0);
+            self.bits(length - 3, 2);
+            self.out.push(offset as u8);
+            return;
+        }
+
+        self.bit(1);
+        let length_bits = if length <= 9 { length - 2 } else { 0 };
+        self.out.push(((length_bits << 5) | (offset >> 8)) as u8);
+        self.out.push(offset as u8);
+        if length <= 9 {
+            return;
+        }
+
+        if length <= 25 {
+            self.bit(0);
+            self.bits(length - 10, 4);
+        } else {
+            self.bit(1);
+            self.out.push((length - 26) as u8);
+        }
+    }
 }
 
-pub fn encode_jpk_lz(decoded_buffer: &[u8]) -> Vec<u8> {
-    let mut out_buffer: Vec<u8> = Vec::new();
-    let mut flag_idx: usize = 0;
-    let mut shift_idx: i8 = 0;
-
-    let mut pattern_dict: HashMap<u32, usize> = HashMap::new();
-
-    let mut i: usize = 0;
-    while i < decoded_buffer.len() {
-        //We look for a sequence
-        let max_search_len = std::cmp::min(280, decoded_buffer.len() - i);
-        let sequence_match = find_longest_match(
-            decoded_buffer,
-            &pattern_dict,
-            &decoded_buffer[i..i + max_search_len],
-            3,
-        );
+pub fn encode_jpk_lz(decoded_buffer: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let params = level.match_params();
+    if level == CompressionLevel::Optimal {
+        return encode_jpk_lz_optimal(decoded_buffer, &params);
+    }
+
+    let mut finder = MatchFinder::new(decoded_buffer, &params);
+    let mut writer = LzWriter::new();
 
-        let encodable_match = sequence_match.and_then(|(match_start_idx, length)| {
-            let relative_offset = i - match_start_idx - 1;
+    let mut pos = 0;
+    // Literals are written once the next match is known, so long stretches become runs
+    let mut literal_start = 0;
+    // Match found for `pos` while checking the previous position lazily
+    let mut lookahead: Option<Option<Match>> = None;
+    while pos < decoded_buffer.len() {
+        let found = lookahead.take().unwrap_or_else(|| finder.find(pos));
+        finder.insert(pos);
+
+        let Some(m) = found else {
+            pos += 1;
+            continue;
+        };
 
-            if relative_offset >= i {
-                return None;
+        if params.lazy && m.length < params.nice_length {
+            let next = finder.find(pos + 1);
+            if next.is_some_and(|next| next.length > m.length) {
+                lookahead = Some(next);
+                pos += 1;
+                continue;
             }
+        }
+
+        writer.literals(&decoded_buffer[literal_start..pos]);
+        writer.back_ref(m);
+        for skipped in pos + 1..pos + m.length {
+            finder.insert(skipped);
+        }
+        pos += m.length;
+        literal_start = pos;
+    }
+    writer.literals(&decoded_buffer[literal_start..]);
+
+    writer.out
+}
+
+/// Picks the cheapest sequence of tokens for each block, pricing every token in bits.
+fn encode_jpk_lz_optimal(decoded_buffer: &[u8], params: &MatchParams) -> Vec<u8> {
+    let mut finder = MatchFinder::new(decoded_buffer, params);
+    let mut writer = LzWriter::new();
+
+    let mut block_start = 0;
+    while block_start < decoded_buffer.len() {
+        let block_end = decoded_buffer.len().min(block_start + OPTIMAL_BLOCK_SIZE);
+        let steps = parse_block(&mut finder, block_start, block_end);
+
+        let mut tokens = Vec::new();
+        let mut pos = block_end - block_start;
+        while pos > 0 {
+            let step = steps[pos];
+            tokens.push((pos, step));
+            pos -= match step {
+                Step::Literal => 1,
+                Step::Match(m) => m.length,
+                Step::LiteralRun(len) => len,
+            };
+        }
 
-            if relative_offset < 8191 {
-                Some((relative_offset, length))
-            } else {
-                None
+        for (end, step) in tokens.into_iter().rev() {
+            match step {
+                Step::Literal => writer.literal(decoded_buffer[block_start + end - 1]),
+                Step::Match(m) => writer.back_ref(m),
+                Step::LiteralRun(len) => {
+                    let end = block_start + end;
+                    writer.literal_run(&decoded_buffer[end - len..end]);
+                }
             }
-        });
-
-        //If we didn't find a sequence
-        if encodable_match.is_none() {
-            set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 0);
-            out_buffer.push(decoded_buffer[i]);
-            if i >= 3 && i + 3 <= decoded_buffer.len() {
-                let hash = calculate_hash(&decoded_buffer[i..i + 3]);
-                pattern_dict.insert(hash, i);
+        }
+        block_start = block_end;
+    }
+
+    writer.out
+}
+
+/// Returns, for every position of the block relative to its start, the last step of the
+/// cheapest encoding of the block up to there.
+fn parse_block(finder: &mut MatchFinder, block_start: usize, block_end: usize) -> Vec<Step> {
+    let block_len = block_end - block_start;
+    let mut cost = vec![u32::MAX; block_len + 1];
+    let mut steps = vec![Step::Literal; block_len + 1];
+    cost[0] = 0;
+
+    let mut relax = |cost: &mut [u32], to: usize, bits: u32, step: Step| {
+        if bits < cost[to] {
+            cost[to] = bits;
+            steps[to] = step;
+        }
+    };
+
+    // Run starts by increasing `cost - 8 * start`, the front is the cheapest run start
+    let mut run_starts: VecDeque<usize> = VecDeque::new();
+    let mut candidates = Vec::new();
+    let mut skip_until = 0;
+    for pos in 0..block_len {
+        if let Some(start) = pos.checked_sub(MIN_LITERAL_RUN) {
+            let key = |i: usize| cost[i] as i64 - 8 * i as i64;
+            while run_starts.back().is_some_and(|&i| key(i) >= key(start)) {
+                run_starts.pop_back();
             }
-            if i >= 8192 {
-                let old_pos = i - 8192;
-                let old_hash = calculate_hash(&decoded_buffer[old_pos..old_pos + 3]);
-                if let Some(index) = pattern_dict.get(&old_hash) {
-                    if *index == old_pos {
-                        pattern_dict.remove(&old_hash);
-                    }
-                }
+            run_starts.push_back(start);
+            while run_starts
+                .front()
+                .is_some_and(|&i| i + MAX_LITERAL_RUN < pos)
+            {
+                run_starts.pop_front();
             }
-            i += 1;
-        } else {
-            let (relative_offset, length) = encodable_match.unwrap();
-            //We found a backref, setting the current bit to 1
-            set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 1);
-
-            if relative_offset < 256 && length <= 6 {
-                //short backref, set bit to 0
-                set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 0);
-                let bit_length = (length - 3) as u8;
-                set_bit_flag(
-                    &mut out_buffer,
-                    &mut flag_idx,
-                    &mut shift_idx,
-                    bit_length >> 1,
-                );
-                set_bit_flag(
-                    &mut out_buffer,
-                    &mut flag_idx,
-                    &mut shift_idx,
-                    bit_length & 1,
-                );
-                out_buffer.push(relative_offset as u8);
-            } else if relative_offset < 8192 && length <= 9 {
-                set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 1);
-                //long ref mode
-                let high_byte = (((length - 2) & 0x7) << 5) | ((relative_offset >> 8) & 0x1F);
-                let low_byte = relative_offset as u8;
-
-                out_buffer.push(high_byte as u8);
-                out_buffer.push(low_byte);
-            } else if relative_offset < 8192 && length <= 280 {
-                set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 1);
-                //long ref mode
-                let high_byte = (relative_offset >> 8) & 0x1F;
-                let low_byte = relative_offset as u8;
-
-                out_buffer.push(high_byte as u8);
-                out_buffer.push(low_byte);
-
-                if length <= 25 {
-                    //write the special bit as 0
-                    set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 0);
-                    let encoded_length = (length - 10) as u8;
-                    //Write the length with the next 4 bits
-                    set_bit_flag(
-                        &mut out_buffer,
-                        &mut flag_idx,
-                        &mut shift_idx,
-                        encoded_length >> 3 & 1,
-                    );
-                    set_bit_flag(
-                        &mut out_buffer,
-                        &mut flag_idx,
-                        &mut shift_idx,
-                        encoded_length >> 2 & 1,
-                    );
-                    set_bit_flag(
-                        &mut out_buffer,
-                        &mut flag_idx,
-                        &mut shift_idx,
-                        encoded_length >> 1 & 1,
-                    );
-                    set_bit_flag(
-                        &mut out_buffer,
-                        &mut flag_idx,
-                        &mut shift_idx,
-                        encoded_length & 1,
-                    );
-                } else {
-                    //special case bit to 1
-                    set_bit_flag(&mut out_buffer, &mut flag_idx, &mut shift_idx, 1);
-                    //push the length as a full byte
-                    out_buffer.push((length - 26) as u8);
-                }
+            if let Some(&start) = run_starts.front() {
+                let len = pos - start;
+                let bits = cost[start] + LITERAL_RUN_BITS + 8 * len as u32;
+                relax(&mut cost, pos, bits, Step::LiteralRun(len));
             }
+        }
+
+        let here = cost[pos];
+        relax(&mut cost, pos + 1, here + LITERAL_BITS, Step::Literal);
 
-            for j in 0..length {
-                if i + j >= 3 && i + j + 3 <= decoded_buffer.len() {
-                    let hash = calculate_hash(&decoded_buffer[i + j..i + j + 3]);
-                    pattern_dict.insert(hash, i + j);
+        if pos >= skip_until {
+            candidates.clear();
+            finder.search(block_start + pos, block_end, |m| candidates.push(m));
+
+            match candidates.last() {
+                Some(&longest) if longest.length >= OPTIMAL_NICE_LENGTH => {
+                    relax(
+                        &mut cost,
+                        pos + longest.length,
+                        here + longest.bits(),
+                        Step::Match(longest),
+                    );
+                    skip_until = pos + longest.length;
                 }
-                if i + j >= 8192 {
-                    let old_pos = i + j - 8192;
-                    let old_hash = calculate_hash(&decoded_buffer[old_pos..old_pos + 3]);
-                    if let Some(index) = pattern_dict.get(&old_hash) {
-                        if *index == old_pos {
-                            pattern_dict.remove(&old_hash);
+                _ => {
+                    let mut length = MIN_MATCH;
+                    for &candidate in &candidates {
+                        while length <= candidate.length {
+                            let m = Match {
+                                distance: candidate.distance,
+                                length,
+                            };
+                            relax(&mut cost, pos + length, here + m.bits(), Step::Match(m));
+                            length += 1;
                         }
                     }
                 }
             }
+        }
+        finder.insert(block_start + pos);
+    }
 
-            i += length;
+    // Runs ending at the block end
+    if let Some(start) = block_len.checked_sub(MIN_LITERAL_RUN) {
+        let lowest = block_len.saturating_sub(MAX_LITERAL_RUN);
+        for start in lowest..=start {
+            let len = block_len - start;
+            let bits = cost[start] + LITERAL_RUN_BITS + 8 * len as u32;
+            relax(&mut cost, block_len, bits, Step::LiteralRun(len));
         }
     }
 
-    out_buffer
+    steps
 }
 
 fn count_frequencies(data_bytes: &[u8]) -> [usize; 256] {
@@ -301,23 +544,32 @@ fn build_huffman_tree(frequencies: [usize; 256]) -> HuffmanNode {
         );
     }
 
-    while pq.len() > 1 {
-        let node_0 = pq.pop().unwrap();
-        let node_1 = pq.pop().unwrap();
+    // The JPK table needs an internal root, empty or single byte data gets unused leaves
+    let missing = 2usize.saturating_sub(pq.len());
+    for byte in (0..=255u8)
+        .filter(|&b| frequencies[b as usize] == 0)
+        .take(missing)
+    {
+        pq.push(HuffmanNode::Leaf { freq: 0, byte }, Reverse(0));
+    }
+
+    while let Some((node_0, Reverse(freq_0))) = pq.pop() {
+        let Some((node_1, Reverse(freq_1))) = pq.pop() else {
+            return node_0;
+        };
 
-        let internal_freq = node_0.1.0 + node_1.1.0;
+        let internal_freq = freq_0 + freq_1;
 
         let internal_node = HuffmanNode::Internal {
             freq: internal_freq,
-            left: Box::new(node_0.0),
-            right: Box::new(node_1.0),
+            left: Box::new(node_0),
+            right: Box::new(node_1),
         };
 
         pq.push(internal_node, Reverse(internal_freq));
     }
 
-    let huffman_root = pq.pop().unwrap();
-    huffman_root.0
+    unreachable!("the queue always starts with two leaves")
 }
 
 fn generate_code_map(
@@ -341,83 +593,45 @@ fn generate_code_map(
     }
 }
 
-fn assign_indexes<'a>(
-    huffman_node: &'a HuffmanNode,
-    depth: usize,
-    next_idx: &mut usize,
-    out_map: &mut HashMap<&'a HuffmanNode, usize>,
+/// Writes the children of `huffman_node` to the pair at `index`, numbering internal
+/// children from `next_index` in depth first order.
+fn serialize_node(
+    huffman_node: &HuffmanNode,
+    index: usize,
+    next_index: &mut usize,
+    jpk_table: &mut [u16],
 ) {
-    match huffman_node {
-        HuffmanNode::Leaf { freq: _, byte: _ } => {}
-        HuffmanNode::Internal {
-            freq: _,
-            left,
-            right,
-        } => {
-            if depth == 0 {
-                out_map.insert(huffman_node, 510);
-            } else {
-                out_map.insert(huffman_node, *next_idx);
-                *next_idx += 1;
-            }
-
-            assign_indexes(left, depth + 1, next_idx, out_map);
-            assign_indexes(right, depth + 1, next_idx, out_map);
+    if let HuffmanNode::Internal {
+        freq: _,
+        left,
+        right,
+    } = huffman_node
+    {
+        let pair_start = (index - 0x100) * 2;
+        for (slot, child) in [left, right].into_iter().enumerate() {
+            jpk_table[pair_start + slot] = match &**child {
+                HuffmanNode::Leaf { freq: _, byte } => *byte as u16,
+                internal => {
+                    let child_index = *next_index;
+                    *next_index += 1;
+                    serialize_node(internal, child_index, next_index, jpk_table);
+                    child_index as u16
+                }
+            };
         }
     }
 }
 
 fn serialize_jpk_table(huffman_root: &HuffmanNode) -> Vec<u16> {
     let mut jpk_table: Vec<u16> = vec![0; 510];
-    let mut index_map: HashMap<&HuffmanNode, usize> = HashMap::new();
     let mut next_index: usize = 0x100;
-    assign_indexes(huffman_root, 0, &mut next_index, &mut index_map);
-
-    for (node, &index) in &index_map {
-        if let HuffmanNode::Internal {
-            freq: _,
-            left,
-            right,
-        } = node
-        {
-            let pair_start = (index - 0x100) * 2;
-
-            match &**left {
-                HuffmanNode::Leaf { freq: _, byte } => {
-                    jpk_table[pair_start] = *byte as u16;
-                }
-
-                internal_left if index_map.contains_key(internal_left) => {
-                    jpk_table[pair_start] = index_map[internal_left] as u16;
-                }
-
-                _ => {
-                    eprintln!("error map")
-                }
-            }
-
-            match &**right {
-                HuffmanNode::Leaf { freq: _, byte } => {
-                    jpk_table[pair_start + 1] = *byte as u16;
-                }
-
-                internal_right if index_map.contains_key(internal_right) => {
-                    jpk_table[pair_start + 1] = index_map[internal_right] as u16;
-                }
-
-                _ => {
-                    eprintln!("error map")
-                }
-            }
-        }
-    }
-
+    serialize_node(huffman_root, 510, &mut next_index, &mut jpk_table);
     jpk_table
 }
 
-pub fn encode_jpk_huff_lz(buffer: &[u8]) -> Vec<u8> {
+pub fn encode_jpk_huff_lz(buffer: &[u8], level: CompressionLevel) -> Vec<u8> {
     //First we encode the buffer with lz compression
-    let lz_buffer = encode_jpk_lz(buffer);
+    let lz_buffer = encode_jpk_lz(buffer, level);
     //Then we count the byte frequencies of our buffer
     let frequencies = count_frequencies(&lz_buffer);
 
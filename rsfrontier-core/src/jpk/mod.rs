@@ -1,108 +1,145 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use core::fmt;
+use byteorder::{LittleEndian, WriteBytesExt};
 use decode::{decode_jpk_huff, decode_jpk_huff_lz, decode_jpk_lz, decode_jpk_raw};
 use encode::{encode_jpk_huff, encode_jpk_huff_lz, encode_jpk_lz};
-use std::{
-    io::{Cursor, Error},
-    path::{Path, PathBuf},
-};
+use std::{io::Cursor, path::Path};
+
+use crate::error::{Error, Format, ReadFormatExt, Result};
 
 mod decode;
 mod encode;
 
 const JPK_EXTENSIONS: [&str; 3] = ["bin", "fmod", "fskl"];
-
-#[derive(Debug)]
-pub enum JpkError {
-    InvalidType(u16),
-    IoError(Error),
-}
-
-impl fmt::Display for JpkError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            JpkError::InvalidType(val) => write!(f, "Invalid JPK Type Found {}", val),
-            JpkError::IoError(err) => write!(f, "I/O Error {}", err),
-        }
-    }
-}
-
-impl std::error::Error for JpkError {}
-
-impl From<Error> for JpkError {
-    fn from(value: Error) -> Self {
-        JpkError::IoError(value)
-    }
-}
-
-impl From<JpkError> for Error {
-    fn from(value: JpkError) -> Self {
-        match value {
-            JpkError::InvalidType(val) => Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid JPK Type value: {}", val),
-            ),
-            JpkError::IoError(e) => e,
-        }
-    }
-}
+const JPK_MAGIC: u32 = 0x1A524B4A;
 
 #[derive(Debug)]
 pub struct JpkHeader {
     magic: u32,
+    #[allow(dead_code)]
     version: u16,
-    comp_type: JpkType,
+    pub(crate) comp_type: JpkType,
     start_offset: usize,
     out_size: usize,
 }
 
-pub fn parse_header(data: &[u8]) -> Result<JpkHeader, Error> {
+pub fn parse_header(data: &[u8]) -> Result<JpkHeader> {
     let mut cursor = Cursor::new(data);
     let header = JpkHeader {
-        magic: cursor.read_u32::<LittleEndian>()?,
-        version: cursor.read_u16::<LittleEndian>()?,
-        comp_type: JpkType::try_from(cursor.read_u16::<LittleEndian>()?)?,
-        start_offset: cursor.read_u32::<LittleEndian>()? as usize,
-        out_size: cursor.read_u32::<LittleEndian>()? as usize,
+        magic: cursor.read_u32_for(Format::Jpk)?,
+        version: cursor.read_u16_for(Format::Jpk)?,
+        comp_type: JpkType::try_from(cursor.read_u16_for(Format::Jpk)?)?,
+        start_offset: cursor.read_u32_for(Format::Jpk)? as usize,
+        out_size: cursor.read_u32_for(Format::Jpk)? as usize,
     };
+
+    if header.magic != JPK_MAGIC {
+        return Err(Error::malformed(Format::Jpk, 0, "bad magic"));
+    }
+
     Ok(header)
 }
 
-pub fn decode_jpk(data: &[u8]) -> Vec<u8> {
-    let header = parse_header(data).unwrap();
-    let file_data_off = header.start_offset as usize;
-    let file_data = &data[file_data_off..];
+pub fn decode_jpk(data: &[u8]) -> Result<Vec<u8>> {
+    let header = parse_header(data)?;
+    let file_data_off = header.start_offset;
+    let file_data = data.get(file_data_off..).ok_or(Error::Truncated {
+        format: Format::Jpk,
+        offset: data.len(),
+    })?;
 
     match header.comp_type {
-        JpkType::Raw => decode_jpk_raw(file_data, header.out_size as usize),
-        JpkType::HuffmanRw => decode_jpk_huff(file_data),
-        JpkType::Lz => decode_jpk_lz(file_data, header.out_size as usize),
-        JpkType::Huffman => decode_jpk_huff_lz(file_data, header.out_size as usize),
+        JpkType::Raw => decode_jpk_raw(file_data, header.out_size),
+        JpkType::HuffmanRw => decode_jpk_huff(file_data).and_then(|mut out| {
+            // The padding bits of the last byte can decode to bytes past the data
+            if out.len() < header.out_size {
+                return Err(Error::Truncated {
+                    format: Format::Jpk,
+                    offset: file_data.len(),
+                });
+            }
+            out.truncate(header.out_size);
+            Ok(out)
+        }),
+        JpkType::Lz => decode_jpk_lz(file_data, header.out_size),
+        JpkType::Huffman => decode_jpk_huff_lz(file_data, header.out_size),
     }
+    .map_err(|e| e.offset_by(file_data_off))
+}
+
+/// How hard the LZ stage of JPK types 3 and 4 searches for matches.
+/// Higher levels produce smaller files but take longer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Default,
+    Max,
+    /// Prices every possible token in bits and keeps the cheapest encoding. Slowest, gives
+    /// the smallest files.
+    Optimal,
 }
 
-pub fn create_jpk(data: &[u8], comp_type: u16) -> Vec<u8> {
-    let magic: u32 = 0x1A524B4A;
+pub fn create_jpk(data: &[u8], comp_type: u16) -> Result<Vec<u8>> {
+    create_jpk_with(data, comp_type, CompressionLevel::default())
+}
+
+pub fn create_jpk_with(data: &[u8], comp_type: u16, level: CompressionLevel) -> Result<Vec<u8>> {
     let version: u16 = 264;
     let start_offset: u32 = 0x10;
-    let out_size: u32 = data.len().try_into().unwrap();
+    let out_size: u32 = data.len().try_into().map_err(|_| Error::TooLarge {
+        format: Format::Jpk,
+        size: data.len(),
+    })?;
+    let jpk_type = JpkType::try_from(comp_type)?;
     let mut out_vec: Vec<u8> = Vec::new();
 
-    out_vec.write_u32::<LittleEndian>(magic).unwrap();
-    out_vec.write_u16::<LittleEndian>(version).unwrap();
-    out_vec.write_u16::<LittleEndian>(comp_type).unwrap();
-    out_vec.write_u32::<LittleEndian>(start_offset).unwrap();
-    out_vec.write_u32::<LittleEndian>(out_size).unwrap();
+    out_vec.write_u32::<LittleEndian>(JPK_MAGIC)?;
+    out_vec.write_u16::<LittleEndian>(version)?;
+    out_vec.write_u16::<LittleEndian>(comp_type)?;
+    out_vec.write_u32::<LittleEndian>(start_offset)?;
+    out_vec.write_u32::<LittleEndian>(out_size)?;
 
-    let packed_buf = match JpkType::try_from(comp_type).unwrap() {
+    let packed_buf = match jpk_type {
         JpkType::Raw => data.to_vec(),
         JpkType::HuffmanRw => encode_jpk_huff(data),
-        JpkType::Lz => encode_jpk_lz(data),
-        JpkType::Huffman => encode_jpk_huff_lz(data),
+        JpkType::Lz => encode_jpk_lz(data, level),
+        JpkType::Huffman => encode_jpk_huff_lz(data, level),
     };
 
     out_vec.extend(packed_buf);
-    out_vec
+    Ok(out_vec)
+}
+
+/// Types tried by `create_jpk_auto` when no candidates are given.
+pub const AUTO_JPK_TYPES: [u16; 4] = [0, 2, 3, 4];
+
+/// Compresses `data` with every type in `candidates` (all of them when empty) and returns
+/// the smallest result that decodes back to `data`.
+pub fn create_jpk_auto(
+    data: &[u8],
+    candidates: &[u16],
+    level: CompressionLevel,
+) -> Result<Vec<u8>> {
+    let candidates = if candidates.is_empty() {
+        &AUTO_JPK_TYPES[..]
+    } else {
+        candidates
+    };
+
+    let mut best: Option<Vec<u8>> = None;
+    for &comp_type in candidates {
+        let jpk = create_jpk_with(data, comp_type, level)?;
+        if best.as_ref().is_some_and(|best| best.len() <= jpk.len()) {
+            continue;
+        }
+        if decode_jpk(&jpk).is_ok_and(|decoded| decoded == data) {
+            best = Some(jpk);
+        }
+    }
+
+    match best {
+        Some(jpk) => Ok(jpk),
+        None => create_jpk_with(data, JpkType::Raw as u16, level),
+    }
 }
 
 pub fn is_buf_jpk(buffer: &[u8]) -> bool {
@@ -113,7 +150,7 @@ pub fn is_buf_jpk(buffer: &[u8]) -> bool {
             .try_into()
             .unwrap_or_default(),
     );
-    magic == 441600842
+    magic == JPK_MAGIC
 }
 
 pub fn should_jpk_compress(path: &Path, buf: &[u8]) -> bool {
@@ -121,12 +158,12 @@ pub fn should_jpk_compress(path: &Path, buf: &[u8]) -> bool {
         return false;
     }
 
-    if let Some(ext) = path.extension() {
-        if let Some(str_ext) = ext.to_str() {
-            for acc_ext in JPK_EXTENSIONS {
-                if str_ext == acc_ext {
-                    return true;
-                }
+    if let Some(ext) = path.extension()
+        && let Some(str_ext) = ext.to_str()
+    {
+        for acc_ext in JPK_EXTENSIONS {
+            if str_ext == acc_ext {
+                return true;
             }
         }
     }
@@ -143,15 +180,15 @@ pub enum JpkType {
 }
 
 impl TryFrom<u16> for JpkType {
-    type Error = JpkError;
+    type Error = Error;
 
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
+    fn try_from(value: u16) -> std::result::Result<Self, Self::Error> {
         match value {
             0 => Ok(JpkType::Raw),
             2 => Ok(JpkType::HuffmanRw),
             3 => Ok(JpkType::Lz),
             4 => Ok(JpkType::Huffman),
-            _ => Err(JpkError::InvalidType(value)),
+            _ => Err(Error::InvalidJpkType(value)),
         }
     }
 }
@@ -163,10 +200,13 @@ mod test {
     use crate::jpk::encode::encode_jpk_lz;
 
     use super::{
+        CompressionLevel, JpkType, create_jpk, create_jpk_auto, create_jpk_with,
         decode::{decode_jpk_huff_lz, decode_jpk_lz},
+        decode_jpk,
         encode::encode_jpk_huff_lz,
         parse_header,
     };
+    use crate::error::Error;
 
     #[test]
     fn roundtrip_lz() {
@@ -177,11 +217,11 @@ mod test {
         let decomp_buf = decode_jpk_lz(
             &encoded_file[file_header.start_offset..],
             file_header.out_size,
-        );
+        )
+        .unwrap();
 
-        let comp_buf = encode_jpk_lz(&decomp_file);
-        dbg!(comp_buf.len());
-        let comp_decomp_buf = decode_jpk_lz(&comp_buf, file_header.out_size);
+        let comp_buf = encode_jpk_lz(&decomp_file, CompressionLevel::Default);
+        let comp_decomp_buf = decode_jpk_lz(&comp_buf, file_header.out_size).unwrap();
 
         assert_eq!(decomp_buf, decomp_file);
         assert_eq!(decomp_buf, comp_decomp_buf);
@@ -192,9 +232,128 @@ mod test {
         let decomp_file = fs::read("./tests/data/mhfdat_decrypt_decomp.bin").unwrap();
         let size = decomp_file.len();
         println!("encoding data...");
-        let huff_comp = encode_jpk_huff_lz(&decomp_file);
+        let huff_comp = encode_jpk_huff_lz(&decomp_file, CompressionLevel::Default);
         println!("decoding data...");
-        let huff_decomp = decode_jpk_huff_lz(&huff_comp, size);
+        let huff_decomp = decode_jpk_huff_lz(&huff_comp, size).unwrap();
         assert!(decomp_file == huff_decomp, "the buffers are not equal");
     }
+
+    #[test]
+    fn malformed_jpk_is_an_error() {
+        let encoded_file = fs::read("./tests/data/quest_ex_0_comp.bin").unwrap();
+
+        assert!(decode_jpk(&encoded_file[..8]).is_err());
+        assert!(decode_jpk(&encoded_file[..encoded_file.len() / 2]).is_err());
+        assert!(matches!(
+            create_jpk(&encoded_file, 1),
+            Err(Error::InvalidJpkType(1))
+        ));
+
+        // A header claiming 4 GB of output is rejected, not allocated
+        let mut huge = create_jpk(&[1, 2, 3], 3).unwrap();
+        huge[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_jpk(&huge).is_err());
+
+        // Type 2 data that decodes to fewer bytes than the header says
+        let mut short = create_jpk(&[1, 2, 3], 2).unwrap();
+        short[12..16].copy_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(decode_jpk(&short), Err(Error::Truncated { .. })));
+    }
+
+    #[test]
+    fn tiny_data_roundtrips() {
+        for comp_type in [0, 2, 3, 4] {
+            for data in [&[][..], &[5; 10]] {
+                let jpk = create_jpk(data, comp_type).unwrap();
+                let decoded = decode_jpk(&jpk).unwrap();
+                assert_eq!(decoded, data, "type {}", comp_type);
+            }
+        }
+    }
+
+    #[test]
+    fn compression_levels_roundtrip() {
+        let decomp_file = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let game_size = fs::read("./tests/data/quest_ex_0_comp.bin").unwrap().len();
+
+        let mut sizes = Vec::new();
+        for level in [
+            CompressionLevel::Fast,
+            CompressionLevel::Default,
+            CompressionLevel::Max,
+            CompressionLevel::Optimal,
+        ] {
+            for comp_type in [3, 4] {
+                let jpk = create_jpk_with(&decomp_file, comp_type, level).unwrap();
+                assert_eq!(decode_jpk(&jpk).unwrap(), decomp_file);
+            }
+            sizes.push(encode_jpk_lz(&decomp_file, level).len());
+        }
+
+        assert!(sizes.is_sorted_by(|a, b| a >= b));
+        // The game's encoder, without the JPK header
+        assert!(sizes[3] <= game_size - 16);
+    }
+
+    #[test]
+    fn lz_handles_every_token_form() {
+        // Runs give long matches, the pattern gives short and far references
+        let mut data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        data.extend(std::iter::repeat_n(0xAB, 1000));
+        data.extend_from_within(100..400);
+        data.extend((0..5000u32).map(|i| (i % 13 + i / 1000) as u8));
+
+        for level in [CompressionLevel::Fast, CompressionLevel::Optimal] {
+            let comp = encode_jpk_lz(&data, level);
+            assert_eq!(decode_jpk_lz(&comp, data.len()).unwrap(), data);
+        }
+        assert!(encode_jpk_lz(&[], CompressionLevel::Max).is_empty());
+
+        // Incompressible data is stored in literal runs, close to its own size
+        let mut state: u32 = 1;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        for level in [CompressionLevel::Fast, CompressionLevel::Optimal] {
+            let comp = encode_jpk_lz(&noise, level);
+            assert_eq!(decode_jpk_lz(&comp, noise.len()).unwrap(), noise);
+            assert!(comp.len() < noise.len() + noise.len() / 100);
+        }
+
+        // Two runs at the longest length (8218 bytes), then too few bytes for a third run
+        let long_run = &noise[..8218 * 2 + 26];
+        let comp = encode_jpk_lz(long_run, CompressionLevel::Default);
+        assert_eq!(decode_jpk_lz(&comp, long_run.len()).unwrap(), long_run);
+    }
+
+    #[test]
+    fn auto_keeps_the_smallest_type() {
+        let decomp_file = fs::read("./tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let level = CompressionLevel::Default;
+
+        let auto = create_jpk_auto(&decomp_file, &[], level).unwrap();
+        for comp_type in [0, 2, 3, 4] {
+            assert!(
+                auto.len()
+                    <= create_jpk_with(&decomp_file, comp_type, level)
+                        .unwrap()
+                        .len()
+            );
+        }
+        assert_eq!(decode_jpk(&auto).unwrap(), decomp_file);
+
+        let restricted = create_jpk_auto(&decomp_file, &[0, 2], level).unwrap();
+        assert!(matches!(
+            parse_header(&restricted).unwrap().comp_type,
+            JpkType::Raw | JpkType::HuffmanRw
+        ));
+        assert_eq!(decode_jpk(&restricted).unwrap(), decomp_file);
+
+        let empty = create_jpk_auto(&[], &[4], level).unwrap();
+        assert_eq!(decode_jpk(&empty).unwrap(), Vec::<u8>::new());
+        assert!(create_jpk_auto(&decomp_file, &[1], level).is_err());
+    }
 }
@@ -1,20 +1,45 @@
 use std::{
-    fs,
+    collections::BTreeMap,
+    fs, io,
     path::{Path, PathBuf},
 };
 
-use ecd::{decrypt_ecd, encrypt_ecd, is_buf_ecd};
-use jpk::{create_jpk, decode_jpk, is_buf_jpk, should_jpk_compress};
+use ecd::{EcdHeader, EcdParams, decrypt_ecd_unchecked, encrypt_ecd_with, is_buf_ecd};
+use entry::{Archive, StopAt, strip_layers_with};
+use error::{Error, Result};
+use exf::{ExfHeader, ExfParams, decrypt_exf_with_header, encrypt_exf_with, is_buf_exf};
+use jpk::{
+    CompressionLevel, JpkType, create_jpk_auto, create_jpk_with, decode_jpk, is_buf_jpk,
+    parse_header, should_jpk_compress,
+};
 use magic::find_buf_extension;
-use mha::{decode_mha_archive, encode_mha_archive, is_buf_mha};
+use manifest::{
+    Layer, Manifest, ManifestNode, MhaManifestEntry, NodeContent, PACK_MARKER_FILE, PackMarker,
+    to_manifest_path,
+};
+use mha::{MHA_METADATA_FILE, MhaEntry, MhaEntryMetadata, MhaMetadata, encode_mha_archive};
 use queues::{IsQueue, Queue};
-use simple_archive::{decode_simple_archive, encode_simple_archive, is_buf_simple_archive};
+use rayon::prelude::*;
+use simple_archive::{
+    DEFAULT_ALIGNMENT, DEFAULT_CONFIDENCE_THRESHOLD, SimpleArchiveLayout, encode_simple_archive,
+    encode_simple_archive_with_layout, simple_archive_confidence,
+};
 
+pub mod diff;
 pub mod ecd;
+pub mod entry;
+pub mod error;
+pub mod exf;
+pub mod ftxt;
+pub mod inspect;
 pub mod jpk;
 pub mod magic;
+pub mod manifest;
 pub mod mha;
 pub mod simple_archive;
+pub mod string_table;
+pub mod text;
+pub mod verify;
 
 pub struct UnpackedFile {
     pub name: String,
@@ -31,9 +56,15 @@ pub enum UnpackResult {
     Folder(Vec<(String, UnpackedFile)>),
 }
 
+/// Output paths paired with the file contents to write there.
+pub type UnpackedFiles = Vec<(PathBuf, Vec<u8>)>;
+
 pub enum PackType {
-    Ecd,
+    Ecd(EcdParams),
+    Exf(ExfParams),
     Jpk(u16),
+    /// The smallest of the listed JPK types, see `jpk::create_jpk_auto`.
+    JpkAuto(Vec<u16>),
 }
 
 pub enum FolderPackType {
@@ -41,49 +72,305 @@ pub enum FolderPackType {
     MHA(u16, u16),
 }
 
-fn recursive_unpack(
-    current_buffer: &[u8],
-    current_pathbuf: PathBuf,
-    out: &mut Vec<(PathBuf, Vec<u8>)>,
-) {
-    let mut processed_buffer = current_buffer.to_vec();
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    /// Keep unpacking ECD files whose payload doesn't match the header CRC,
+    /// reporting the mismatch in `UnpackOutput::warnings` instead of failing.
+    pub lenient_crc: bool,
+    /// Minimum `simple_archive_confidence` for a buffer to be split as a Simple Archive.
+    pub simple_archive_threshold: f32,
+    /// Forces (`true`) or prevents (`false`) Simple Archive unpacking of single nodes,
+    /// regardless of the threshold. Keys are manifest paths like `mhfdat/0003`,
+    /// a file extension on the last part is ignored.
+    pub simple_archive_overrides: BTreeMap<String, bool>,
+    /// Number of threads archive entries are unpacked on, one per core when `None`.
+    pub jobs: Option<usize>,
+}
 
-    loop {
-        if is_buf_ecd(&processed_buffer) {
-            processed_buffer = decrypt_ecd(&processed_buffer);
-            continue;
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        UnpackOptions {
+            lenient_crc: false,
+            simple_archive_threshold: DEFAULT_CONFIDENCE_THRESHOLD,
+            simple_archive_overrides: BTreeMap::new(),
+            jobs: None,
         }
+    }
+}
 
-        if is_buf_jpk(&processed_buffer) {
-            processed_buffer = decode_jpk(&processed_buffer);
-            continue;
+impl UnpackOptions {
+    fn is_simple_archive(&self, buf: &[u8], manifest_path: &str) -> bool {
+        let forced = self
+            .simple_archive_overrides
+            .iter()
+            .find_map(|(key, &value)| {
+                let key = key.replace('\\', "/");
+                let key = to_manifest_path(&Path::new(&key).with_extension(""), Path::new(""));
+                (key == manifest_path).then_some(value)
+            });
+        forced.unwrap_or_else(|| simple_archive_confidence(buf) >= self.simple_archive_threshold)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// LZ effort of every JPK layer created while packing.
+    pub compression: CompressionLevel,
+    /// JPK type of files compressed because of their extension, in folders without a `PackMarker`.
+    pub jpk_type: JpkSelection,
+    /// Number of threads sibling files and archive entries are packed on, one per core when `None`.
+    pub jobs: Option<usize>,
+}
+
+/// Runs `f` on a pool of `jobs` threads, or on rayon's global pool (one thread per core).
+fn run_with_jobs<T: Send>(jobs: Option<usize>, f: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+    match jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(|e| Error::Io(io::Error::other(e)))?
+            .install(f),
+        None => f(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JpkSelection {
+    Type(u16),
+    /// Try every listed type (all of them when empty) and keep the smallest output.
+    Auto(Vec<u16>),
+}
+
+impl Default for JpkSelection {
+    fn default() -> Self {
+        JpkSelection::Type(JpkType::Lz as u16)
+    }
+}
+
+impl JpkSelection {
+    fn create_jpk(&self, data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+        match self {
+            JpkSelection::Type(comp_type) => create_jpk_with(data, *comp_type, level),
+            JpkSelection::Auto(candidates) => create_jpk_auto(data, candidates, level),
         }
+    }
+}
 
-        if is_buf_simple_archive(&processed_buffer) {
-            let in_buffers = decode_simple_archive(&processed_buffer);
-            for (i, in_buf) in in_buffers.iter().enumerate() {
-                let folder_name = format!("{:04}", i);
-                let mut new_pathbuf = current_pathbuf.clone();
-                new_pathbuf.push(folder_name);
-                recursive_unpack(in_buf, new_pathbuf, out);
-            }
-            return;
-        }
-
-        if is_buf_mha(&processed_buffer) {
-            println!("Found mha archive");
-            let in_buffers = decode_mha_archive(&processed_buffer);
-            for (name, file_buf) in in_buffers {
-                dbg!(name);
-                let mut new_pathbuf = current_pathbuf.clone();
-                new_pathbuf.push(name);
-                new_pathbuf.set_extension("");
-                recursive_unpack(&file_buf, new_pathbuf, out);
+pub struct UnpackOutput {
+    pub files: UnpackedFiles,
+    pub manifest: Manifest,
+    /// Problems that were tolerated because of the options, each wrapped with its layer path.
+    pub warnings: Vec<Error>,
+}
+
+struct UnpackContext<'a> {
+    options: &'a UnpackOptions,
+    root: &'a Path,
+    out: UnpackedFiles,
+    warnings: Vec<Error>,
+}
+
+impl<'a> UnpackContext<'a> {
+    fn new(options: &'a UnpackOptions, root: &'a Path) -> Self {
+        UnpackContext {
+            options,
+            root,
+            out: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Unpacks `children` on the thread pool, each into its own context. The nodes and contexts
+    /// are returned in the order of `children`, so merging them keeps the output deterministic.
+    fn unpack_children<'b>(
+        &self,
+        children: &[(PathBuf, &'b [u8])],
+    ) -> Result<Vec<(ManifestNode, UnpackContext<'a>)>> {
+        let (options, root) = (self.options, self.root);
+        children
+            .par_iter()
+            .map(|(path, buf)| {
+                let mut ctx = UnpackContext::new(options, root);
+                let node = recursive_unpack(buf, path.clone(), &mut ctx)?;
+                Ok((node, ctx))
+            })
+            .collect()
+    }
+
+    fn merge(&mut self, child: UnpackContext) {
+        self.out.extend(child.out);
+        self.warnings.extend(child.warnings);
+    }
+}
+
+/// An encryption or compression layer removed by `strip_layer`, with the data it wrapped.
+pub(crate) enum Stripped {
+    /// The payload isn't checked against the header CRC, that's up to the caller.
+    Ecd(EcdHeader, Vec<u8>),
+    Exf(ExfHeader, Vec<u8>),
+    Jpk(u16, Vec<u8>),
+}
+
+impl Stripped {
+    pub(crate) fn layer(&self) -> Layer {
+        match self {
+            Stripped::Ecd(header, _) => Layer::Ecd {
+                index: header.index,
+                version: header.version,
+            },
+            Stripped::Exf(header, _) => Layer::Exf {
+                index: header.index,
+                version: header.version,
+            },
+            Stripped::Jpk(jpk_type, _) => Layer::Jpk {
+                jpk_type: *jpk_type,
+            },
+        }
+    }
+
+    pub(crate) fn into_data(self) -> Vec<u8> {
+        match self {
+            Stripped::Ecd(_, data) | Stripped::Exf(_, data) | Stripped::Jpk(_, data) => data,
+        }
+    }
+}
+
+/// Removes the outermost ECD, EXF or JPK layer of `buf`, `None` when it has none.
+pub(crate) fn strip_layer(buf: &[u8]) -> Result<Option<Stripped>> {
+    if is_buf_ecd(buf) {
+        let (header, decrypted) = decrypt_ecd_unchecked(buf)?;
+        return Ok(Some(Stripped::Ecd(header, decrypted)));
+    }
+    if is_buf_exf(buf) {
+        let (header, decrypted) = decrypt_exf_with_header(buf)?;
+        return Ok(Some(Stripped::Exf(header, decrypted)));
+    }
+    if is_buf_jpk(buf) {
+        let header = parse_header(buf)?;
+        return Ok(Some(Stripped::Jpk(
+            header.comp_type as u16,
+            decode_jpk(buf)?,
+        )));
+    }
+    Ok(None)
+}
+
+fn recursive_unpack(
+    current_buffer: &[u8],
+    current_pathbuf: PathBuf,
+    ctx: &mut UnpackContext,
+) -> Result<ManifestNode> {
+    let lenient_crc = ctx.options.lenient_crc;
+    let (layers, processed_buffer) = strip_layers_with(
+        current_buffer.to_vec(),
+        &current_pathbuf,
+        StopAt::Data,
+        |stripped, _| {
+            if let Stripped::Ecd(header, decrypted) = stripped
+                && let Err(e) = header.check_crc(decrypted)
+            {
+                if !lenient_crc {
+                    return Err(e);
+                }
+                ctx.warnings.push(e.in_layer(&current_pathbuf));
             }
-            return;
+            Ok(())
+        },
+    )?;
+
+    let archive = Archive::detect(&processed_buffer, &current_pathbuf, ctx.root, ctx.options)
+        .map_err(|e| e.in_layer(&current_pathbuf))?;
+    let children: Vec<_> = archive
+        .iter()
+        .flat_map(|archive| {
+            archive
+                .entry_names()
+                .into_iter()
+                .enumerate()
+                .map(|(i, (folder, _))| (current_pathbuf.join(folder), archive.entry(i)))
+        })
+        .collect();
+
+    if let Some(Archive::Simple { layout, .. }) = &archive {
+        let unpacked = ctx.unpack_children(&children)?;
+        let mut entries = Vec::with_capacity(children.len());
+        let mut marker_children = Vec::with_capacity(children.len());
+        for ((new_pathbuf, _), (node, child_ctx)) in children.iter().zip(unpacked) {
+            ctx.merge(child_ctx);
+            marker_children.push((unpacked_file_name(&node, new_pathbuf), node.clone()));
+            entries.push(node);
         }
+        push_pack_marker(
+            ctx,
+            &current_pathbuf,
+            &layers,
+            Some(layout),
+            &marker_children,
+        )?;
+        return Ok(ManifestNode {
+            layers,
+            content: NodeContent::SimpleArchive {
+                alignment: layout.alignment,
+                offsets: layout.offsets.clone(),
+                size: layout.size,
+                entries,
+            },
+        });
+    }
 
-        break;
+    if let Some(Archive::Mha {
+        base_id,
+        capacity,
+        entries: mha_entries,
+    }) = &archive
+    {
+        let (base_id, capacity) = (*base_id, *capacity);
+        let mut entries = Vec::with_capacity(mha_entries.len());
+        let mut marker_children = Vec::with_capacity(mha_entries.len());
+        let mut metadata = MhaMetadata {
+            base_id,
+            capacity,
+            entries: Vec::with_capacity(mha_entries.len()),
+        };
+        let unpacked = ctx.unpack_children(&children)?;
+        for ((entry, (new_pathbuf, _)), (node, child_ctx)) in
+            mha_entries.iter().zip(&children).zip(unpacked)
+        {
+            ctx.merge(child_ctx);
+            let file_name = unpacked_file_name(&node, new_pathbuf);
+            marker_children.push((file_name.clone(), node.clone()));
+            metadata.entries.push(MhaEntryMetadata {
+                name: entry.name.clone(),
+                file: file_name,
+                id: entry.id,
+                size: entry.size,
+                padded_size: entry.padded_size,
+            });
+            entries.push(MhaManifestEntry {
+                name: entry.name.clone(),
+                id: Some(entry.id),
+                size: Some(entry.size),
+                padded_size: Some(entry.padded_size),
+                node,
+            });
+        }
+        // Kept next to the entries for pack_folder, it isn't an archive entry
+        let metadata_json = metadata
+            .to_json()
+            .map_err(|e| e.in_layer(&current_pathbuf))?;
+        ctx.out.push((
+            current_pathbuf.join(MHA_METADATA_FILE),
+            metadata_json.into_bytes(),
+        ));
+        push_pack_marker(ctx, &current_pathbuf, &layers, None, &marker_children)?;
+        return Ok(ManifestNode {
+            layers,
+            content: NodeContent::Mha {
+                base_id,
+                capacity,
+                entries,
+            },
+        });
     }
 
     let get_file_ext = find_buf_extension(&processed_buffer);
@@ -97,116 +384,409 @@ fn recursive_unpack(
         }
     }
 
-    out.push((final_path_buf, processed_buffer));
+    let node = ManifestNode {
+        layers,
+        content: NodeContent::File {
+            path: to_manifest_path(&final_path_buf, ctx.root),
+        },
+    };
+    ctx.out.push((final_path_buf, processed_buffer));
+    Ok(node)
+}
+
+/// Writes the `PackMarker` of a folder unpacked from an archive wrapped in `layers`,
+/// `children` are the unpacked entries with their file names.
+fn push_pack_marker(
+    ctx: &mut UnpackContext,
+    folder: &Path,
+    layers: &[Layer],
+    layout: Option<&SimpleArchiveLayout>,
+    children: &[(String, ManifestNode)],
+) -> Result<()> {
+    let marker = PackMarker {
+        layers: layers.to_vec(),
+        alignment: layout.map(|layout| layout.alignment),
+        offsets: layout.and_then(|layout| layout.offsets.clone()),
+        size: layout.and_then(|layout| layout.size),
+        // Nested archive folders get their own marker
+        files: children
+            .iter()
+            .filter(|(_, node)| matches!(node.content, NodeContent::File { .. }))
+            .filter(|(_, node)| !node.layers.is_empty())
+            .map(|(name, node)| (name.clone(), node.layers.clone()))
+            .collect(),
+    };
+    let json = marker.to_json().map_err(|e| e.in_layer(folder))?;
+    ctx.out
+        .push((folder.join(PACK_MARKER_FILE), json.into_bytes()));
+    Ok(())
+}
+
+/// Reads the marker written into `folder` when it was unpacked, if any.
+pub fn read_pack_marker(folder: &Path) -> Result<Option<PackMarker>> {
+    let path = folder.join(PACK_MARKER_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let json = fs::read_to_string(&path).map_err(|e| Error::from(e).in_layer(&path))?;
+    PackMarker::from_json(&json)
+        .map(Some)
+        .map_err(|e| e.in_layer(&path))
 }
 
-pub fn recursive_pack(current_path: &Path) -> Queue<(PathBuf, Vec<u8>)> {
+/// Re-applies `layers`, listed outermost first, from the inside out.
+fn apply_layers(buf: Vec<u8>, layers: &[Layer], options: &PackOptions) -> Result<Vec<u8>> {
+    let mut current_buffer = buf;
+    for layer in layers.iter().rev() {
+        current_buffer = match layer {
+            Layer::Ecd { index, version } => encrypt_ecd_with(
+                &current_buffer,
+                EcdParams {
+                    index: *index,
+                    version: *version,
+                },
+            )?,
+            Layer::Exf { index, version } => encrypt_exf_with(
+                &current_buffer,
+                ExfParams {
+                    index: *index,
+                    version: *version,
+                },
+            )?,
+            Layer::Jpk { jpk_type } => {
+                create_jpk_with(&current_buffer, *jpk_type, options.compression)?
+            }
+        };
+    }
+    Ok(current_buffer)
+}
+
+/// Name of the file or folder `node` was unpacked to, `path` is where its unpacking started.
+fn unpacked_file_name(node: &ManifestNode, path: &Path) -> String {
+    match &node.content {
+        NodeContent::File { path } => path.rsplit('/').next().unwrap_or_default().to_string(),
+        _ => path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    }
+}
+
+/// Packs every entry of `current_path`. Folders and files are wrapped in the layers listed in
+/// the folder's `PackMarker`, folders unpacked without one fall back to JPK compressing known
+/// file types.
+pub fn recursive_pack(current_path: &Path) -> Result<Queue<(PathBuf, Vec<u8>)>> {
+    let marker = read_pack_marker(current_path)?;
+    pack_entries(current_path, marker.as_ref(), &PackOptions::default())
+}
+
+/// `marker` is the `PackMarker` of `current_path`, read once by the caller.
+fn pack_entries(
+    current_path: &Path,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<Queue<(PathBuf, Vec<u8>)>> {
     let mut folder_queue: Queue<(PathBuf, Vec<u8>)> = Queue::new();
     if current_path.is_dir() {
         let mut entries: Vec<_> = fs::read_dir(current_path)
-            .unwrap()
-            .filter_map(Result::ok)
+            .map_err(|e| Error::from(e).in_layer(current_path))?
+            .filter_map(std::result::Result::ok)
             .collect();
 
         entries.sort_by_key(|e| e.file_name());
 
-        for entry in entries {
-            let entry_path = entry.path();
+        //Skips metadata files
+        entries.retain(|entry| !entry.file_name().to_string_lossy().starts_with("."));
 
-            //Skips metadata files
-            if let Some(entry_name) = entry_path.file_name() {
-                if entry_name.to_string_lossy().starts_with(".") {
-                    continue;
-                }
-            }
-
-            if entry_path.is_dir() {
-                let mut sub_folder_queue = recursive_pack(&entry_path);
-                let mut simple_archive_vec = Vec::new();
-                while sub_folder_queue.size() > 0 {
-                    let file = sub_folder_queue.remove().unwrap();
-                    simple_archive_vec.push(file.1);
-                }
-                let simple_archive_buf = encode_simple_archive(&simple_archive_vec);
-                let mut file_pathbuf = entry_path.clone();
-                let file_ext = find_buf_extension(&simple_archive_buf);
-                file_pathbuf.set_extension(file_ext);
-                let _ = folder_queue.add((file_pathbuf, simple_archive_buf));
-            } else {
-                let file_buf = fs::read(&entry_path).unwrap();
-                let mut file_pathbuf = entry_path.clone();
-                if should_jpk_compress(&entry_path, &file_buf) {
-                    let comp_buf = create_jpk(&file_buf, 3);
-                    let file_ext = find_buf_extension(&comp_buf);
-                    file_pathbuf.set_extension(file_ext);
-                    let _ = folder_queue.add((file_pathbuf, comp_buf)).unwrap();
-                } else {
-                    let file_ext = find_buf_extension(&file_buf);
-                    file_pathbuf.set_extension(file_ext);
-                    let _ = folder_queue.add((file_pathbuf, file_buf)).unwrap();
-                }
-            }
+        // Siblings are packed in parallel, the ordered collect keeps the queue sorted by name
+        let packed = entries
+            .par_iter()
+            .map(|entry| pack_entry(&entry.path(), marker, options))
+            .collect::<Result<Vec<_>>>()?;
+        for file in packed {
+            let _ = folder_queue.add(file);
         }
     }
-    folder_queue
+    Ok(folder_queue)
 }
 
-pub fn unpack_buffer(prefix_path: &str, buf: &[u8]) -> Vec<(PathBuf, Vec<u8>)> {
-    let mut out = Vec::new();
-    let base_path = PathBuf::from(prefix_path);
-    recursive_unpack(buf, base_path, &mut out);
-    out
+/// Packs a single entry of a folder, `marker` is the folder's `PackMarker`.
+fn pack_entry(
+    entry_path: &Path,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<(PathBuf, Vec<u8>)> {
+    let packed_buf = if entry_path.is_dir() {
+        pack_archive_folder(entry_path, options)?
+    } else {
+        let file_buf = fs::read(entry_path).map_err(|e| Error::from(e).in_layer(entry_path))?;
+        if let Some(marker) = marker {
+            let file_name = entry_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let layers = marker.files.get(&file_name).map(Vec::as_slice);
+            apply_layers(file_buf, layers.unwrap_or_default(), options)
+                .map_err(|e| e.in_layer(entry_path))?
+        } else if should_jpk_compress(entry_path, &file_buf) {
+            options
+                .jpk_type
+                .create_jpk(&file_buf, options.compression)
+                .map_err(|e| e.in_layer(entry_path))?
+        } else {
+            file_buf
+        }
+    };
+    let mut file_pathbuf = entry_path.to_path_buf();
+    file_pathbuf.set_extension(find_buf_extension(&packed_buf));
+    Ok((file_pathbuf, packed_buf))
 }
 
-pub fn pack_buffer(buf: &[u8], pack_type: PackType) -> Vec<u8> {
-    let mut current_buffer = buf.to_vec();
+/// Packs a nested folder as an MHA archive if it has MHA metadata, as a Simple Archive
+/// otherwise, then applies the layers of its `PackMarker`.
+fn pack_archive_folder(folder: &Path, options: &PackOptions) -> Result<Vec<u8>> {
+    let marker = read_pack_marker(folder)?;
+    let archive_buf = if let Some(metadata) = read_mha_metadata(folder)? {
+        pack_mha_folder(
+            folder,
+            metadata.base_id,
+            metadata.capacity,
+            Some(&metadata),
+            marker.as_ref(),
+            options,
+        )?
+    } else {
+        pack_simple_folder(folder, marker.as_ref(), options)?
+    };
+    apply_marker_layers(folder, archive_buf, marker.as_ref(), options)
+}
 
-    match pack_type {
-        PackType::Ecd => {
-            current_buffer = encrypt_ecd(&current_buffer);
-        }
-        PackType::Jpk(jpk_type) => {
-            current_buffer = create_jpk(&current_buffer, jpk_type);
+fn pack_simple_folder(
+    folder: &Path,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    let layout = match marker {
+        Some(marker) => SimpleArchiveLayout {
+            alignment: marker.alignment.unwrap_or(DEFAULT_ALIGNMENT),
+            offsets: marker.offsets.clone(),
+            size: marker.size,
+        },
+        None => SimpleArchiveLayout::aligned(DEFAULT_ALIGNMENT),
+    };
+    let mut folder_queue = pack_entries(folder, marker, options)?;
+    let mut simple_archive_vec = Vec::new();
+    while let Ok(file) = folder_queue.remove() {
+        simple_archive_vec.push(file.1);
+    }
+    encode_simple_archive_with_layout(&simple_archive_vec, &layout).map_err(|e| e.in_layer(folder))
+}
+
+fn apply_marker_layers(
+    folder: &Path,
+    archive_buf: Vec<u8>,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    match marker {
+        Some(marker) => {
+            apply_layers(archive_buf, &marker.layers, options).map_err(|e| e.in_layer(folder))
         }
+        None => Ok(archive_buf),
     }
+}
 
-    current_buffer
+pub fn unpack_buffer(prefix_path: &str, buf: &[u8]) -> Result<UnpackedFiles> {
+    unpack_buffer_with_manifest(prefix_path, buf).map(|(out, _)| out)
 }
 
-pub fn pack_folder(folder_path: &Path, pack_type: FolderPackType) -> Vec<u8> {
-    let mut folder_queue = recursive_pack(folder_path);
+/// Unpacks like `unpack_buffer` and also returns a manifest of every layer that was stripped.
+/// File paths in the manifest are relative to the parent of `prefix_path`, which is where
+/// `manifest::manifest_path` places the manifest file.
+pub fn unpack_buffer_with_manifest(
+    prefix_path: &str,
+    buf: &[u8],
+) -> Result<(UnpackedFiles, Manifest)> {
+    unpack_buffer_with_options(prefix_path, buf, &UnpackOptions::default())
+        .map(|output| (output.files, output.manifest))
+}
 
+pub fn unpack_buffer_with_options(
+    prefix_path: &str,
+    buf: &[u8],
+    options: &UnpackOptions,
+) -> Result<UnpackOutput> {
+    run_with_jobs(options.jobs, || {
+        unpack_on_current_pool(Path::new(prefix_path), buf, options)
+    })
+}
+
+/// `unpack_buffer_with_options` without setting up a thread pool.
+fn unpack_on_current_pool(
+    base_path: &Path,
+    buf: &[u8],
+    options: &UnpackOptions,
+) -> Result<UnpackOutput> {
+    let root = base_path.parent().unwrap_or(Path::new(""));
+    let mut ctx = UnpackContext::new(options, root);
+    let root_node = recursive_unpack(buf, base_path.to_path_buf(), &mut ctx)?;
+    Ok(UnpackOutput {
+        files: ctx.out,
+        manifest: Manifest::new(root_node),
+        warnings: ctx.warnings,
+    })
+}
+
+pub fn pack_buffer(buf: &[u8], pack_type: PackType) -> Result<Vec<u8>> {
+    pack_buffer_with_options(buf, pack_type, &PackOptions::default())
+}
+
+pub fn pack_buffer_with_options(
+    buf: &[u8],
+    pack_type: PackType,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
     match pack_type {
-        FolderPackType::Simple => {
-            let mut simple_archive_vec = Vec::new();
-            while folder_queue.size() > 0 {
-                let file = folder_queue.remove().unwrap();
-                simple_archive_vec.push(file.1);
-            }
-            encode_simple_archive(&simple_archive_vec)
-        }
-        FolderPackType::MHA(base_file_id, capacity) => {
-            let mut mha_vec = Vec::new();
-            while folder_queue.size() > 0 {
-                let file = folder_queue.remove().unwrap();
-                if let Some(file_name) = file.0.file_name() {
-                    mha_vec.push((file_name.to_string_lossy().to_string(), file.1));
-                }
-            }
-            encode_mha_archive(mha_vec, base_file_id, capacity)
+        PackType::Ecd(params) => encrypt_ecd_with(buf, params),
+        PackType::Exf(params) => encrypt_exf_with(buf, params),
+        PackType::Jpk(jpk_type) => create_jpk_with(buf, jpk_type, options.compression),
+        PackType::JpkAuto(candidates) => create_jpk_auto(buf, &candidates, options.compression),
+    }
+}
+
+/// Reads the `.metadata` file written when an MHA archive was unpacked to `folder`, if any.
+pub fn read_mha_metadata(folder: &Path) -> Result<Option<MhaMetadata>> {
+    let path = folder.join(MHA_METADATA_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path).map_err(|e| Error::from(e).in_layer(&path))?;
+    MhaMetadata::parse(&text)
+        .map(Some)
+        .map_err(|e| e.in_layer(&path))
+}
+
+/// Packs `folder` as an MHA archive. Files listed in `metadata` get back their archive name,
+/// ID, sizes and position, other files are appended with the next free IDs.
+fn pack_mha_folder(
+    folder: &Path,
+    base_id: u16,
+    capacity: u16,
+    metadata: Option<&MhaMetadata>,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    let mut folder_queue = pack_entries(folder, marker, options)?;
+    let metadata_entries = metadata.map(|m| m.entries.as_slice()).unwrap_or_default();
+
+    let mut known = Vec::new();
+    let mut unknown = Vec::new();
+    while let Ok((path, data)) = folder_queue.remove() {
+        let found = metadata_entries
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| path.file_name() == Some(entry.file.as_ref()));
+        match found {
+            Some((position, entry)) => known.push((
+                position,
+                MhaEntry {
+                    name: entry.name.clone(),
+                    id: entry.id,
+                    size: entry.size,
+                    padded_size: entry.padded_size,
+                    data,
+                },
+            )),
+            None => unknown.push((path, data)),
         }
     }
+    known.sort_by_key(|(position, _)| *position);
+
+    let mut mha_vec: Vec<MhaEntry> = known.into_iter().map(|(_, entry)| entry).collect();
+    // IDs come from the user-editable `.metadata`, the highest one may leave no room
+    let mut next_id = match mha_vec.iter().map(|entry| entry.id).max() {
+        Some(id) => id.checked_add(1),
+        None => Some(base_id as u32),
+    };
+    for (path, data) in unknown {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let id = next_id.ok_or_else(|| {
+            Error::Manifest(format!("no entry ID left for '{}'", file_name)).in_layer(folder)
+        })?;
+        mha_vec.push(MhaEntry::new(file_name.to_string(), id, data));
+        next_id = id.checked_add(1);
+    }
+
+    encode_mha_archive(&mha_vec, base_id, capacity).map_err(|e| e.in_layer(folder))
+}
+
+/// Packs `folder_path`. MHA archives also pick up entry details from the folder's `.metadata`
+/// file, see `read_mha_metadata`. Layers listed in the folder's `PackMarker` (written when it
+/// was unpacked) are applied to the result, including encryption.
+pub fn pack_folder(folder_path: &Path, pack_type: FolderPackType) -> Result<Vec<u8>> {
+    pack_folder_with_options(folder_path, pack_type, &PackOptions::default())
+}
+
+pub fn pack_folder_with_options(
+    folder_path: &Path,
+    pack_type: FolderPackType,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    let marker = read_pack_marker(folder_path)?;
+    pack_folder_with_marker(folder_path, pack_type, marker.as_ref(), options)
+}
+
+fn pack_folder_with_marker(
+    folder_path: &Path,
+    pack_type: FolderPackType,
+    marker: Option<&PackMarker>,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    run_with_jobs(options.jobs, || {
+        let archive_buf = match pack_type {
+            FolderPackType::Simple => pack_simple_folder(folder_path, marker, options)?,
+            FolderPackType::MHA(base_file_id, capacity) => {
+                let metadata = read_mha_metadata(folder_path)?;
+                pack_mha_folder(
+                    folder_path,
+                    base_file_id,
+                    capacity,
+                    metadata.as_ref(),
+                    marker,
+                    options,
+                )?
+            }
+        };
+        apply_marker_layers(folder_path, archive_buf, marker, options)
+    })
 }
 
 // Special handling for monster archives, if the number of files in the archive is 7, the last file need to be jpk decompressed before going into the simple archive.
-pub fn pack_em_folder(folder_path: &Path) -> Vec<u8> {
-    let mut folder_queue = recursive_pack(folder_path);
+pub fn pack_em_folder(folder_path: &Path) -> Result<Vec<u8>> {
+    pack_em_folder_with_options(folder_path, &PackOptions::default())
+}
+
+pub fn pack_em_folder_with_options(folder_path: &Path, options: &PackOptions) -> Result<Vec<u8>> {
+    // Folders unpacked with a marker already record which entries are compressed
+    let marker = read_pack_marker(folder_path)?;
+    if marker.is_some() {
+        return pack_folder_with_marker(
+            folder_path,
+            FolderPackType::Simple,
+            marker.as_ref(),
+            options,
+        );
+    }
+
+    let mut folder_queue =
+        run_with_jobs(options.jobs, || pack_entries(folder_path, None, options))?;
     let mut simple_archive_vec = Vec::new();
     let mut counter = 0;
-    while folder_queue.size() > 0 {
-        let file = folder_queue.remove().unwrap();
+    while let Ok(file) = folder_queue.remove() {
         if counter == 6 {
-            let decompressed = decode_jpk(&file.1);
+            let decompressed = decode_jpk(&file.1).map_err(|e| e.in_layer(&file.0))?;
             simple_archive_vec.push(decompressed);
         } else {
             simple_archive_vec.push(file.1);
@@ -216,29 +796,497 @@ pub fn pack_em_folder(folder_path: &Path) -> Vec<u8> {
     encode_simple_archive(&simple_archive_vec)
 }
 
+fn repack_node(node: &ManifestNode, root: &Path, options: &PackOptions) -> Result<Vec<u8>> {
+    let current_buffer = match &node.content {
+        NodeContent::File { path } => {
+            let file_path = root.join(path);
+            fs::read(&file_path).map_err(|e| Error::from(e).in_layer(&file_path))?
+        }
+        NodeContent::SimpleArchive {
+            alignment,
+            offsets,
+            size,
+            entries,
+        } => {
+            let files = entries
+                .par_iter()
+                .map(|entry| repack_node(entry, root, options))
+                .collect::<Result<Vec<_>>>()?;
+            let layout = SimpleArchiveLayout {
+                alignment: *alignment,
+                offsets: offsets.clone(),
+                size: *size,
+            };
+            encode_simple_archive_with_layout(&files, &layout)?
+        }
+        NodeContent::Mha {
+            base_id,
+            capacity,
+            entries,
+        } => {
+            let files = entries
+                .par_iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    let data = repack_node(&entry.node, root, options)?;
+                    let mut mha_entry = MhaEntry::new(
+                        entry.name.clone(),
+                        entry.id.unwrap_or(*base_id as u32 + i as u32),
+                        data,
+                    );
+                    if let (Some(size), Some(padded_size)) = (entry.size, entry.padded_size) {
+                        mha_entry.size = size;
+                        mha_entry.padded_size = padded_size;
+                    }
+                    Ok(mha_entry)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            encode_mha_archive(&files, *base_id, *capacity)?
+        }
+    };
+
+    apply_layers(current_buffer, &node.layers, options)
+}
+
+/// Rebuilds the original buffer described by `manifest`, reading leaf files relative to `root`
+/// (the folder the manifest was written to). Edited files on disk replace the original data.
+pub fn repack_from_manifest(manifest: &Manifest, root: &Path) -> Result<Vec<u8>> {
+    repack_from_manifest_with_options(manifest, root, &PackOptions::default())
+}
+
+pub fn repack_from_manifest_with_options(
+    manifest: &Manifest,
+    root: &Path,
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    run_with_jobs(options.jobs, || repack_node(&manifest.root, root, options))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        jpk::create_jpk, mha::decode_mha_archive, simple_archive::encode_simple_archive_aligned,
+    };
 
     #[test]
     fn test_em_roundtrip_deep_compare() {
-        let test_path = Path::new("tests/data/em152-hd");
-        let og_em_archive = fs::read("D:\\FrontierForkedVer\\Client\\Monster Hunter Frontier Online\\dat\\emmodel-hd\\em152-hd.pac").unwrap();
-        let unpacked_files = unpack_buffer(test_path.to_str().unwrap(), &og_em_archive);
-        for (path, buf) in &unpacked_files {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).unwrap();
+        // Shaped like a monster archive: seven entries, JPK compressed but for the last one
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let mut files: Vec<_> = (0..6)
+            .map(|i| create_jpk(&uncomp[i * 100..], 3).unwrap())
+            .collect();
+        files.push(uncomp.clone());
+        let em_archive = encode_simple_archive(&files).unwrap();
+
+        let report = verify::verify_buffer(
+            "em152-hd",
+            &em_archive,
+            &UnpackOptions::default(),
+            &PackOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.nodes.len(), 8);
+        assert!(report.is_identical(), "{:?}", report);
+    }
+
+    #[test]
+    fn unpack_error_names_the_failing_layer() {
+        let comp = fs::read("tests/data/quest_ex_0_comp.bin").unwrap();
+        let archive = encode_simple_archive(&[vec![1, 2, 3, 4], comp[..2728].to_vec()]).unwrap();
+
+        match unpack_buffer("out", &archive) {
+            Err(Error::Layer { path, .. }) => assert_eq!(path, Path::new("out/0001")),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("truncated JPK entry was unpacked"),
+        }
+    }
+
+    #[test]
+    fn manifest_records_layers() {
+        let comp = fs::read("tests/data/quest_ex_0_comp.bin").unwrap();
+        let archive = encode_simple_archive(&[vec![1, 2, 3, 4], comp]).unwrap();
+        let encrypted = ecd::encrypt_ecd(&archive).unwrap();
+
+        let (files, manifest) = unpack_buffer_with_manifest("out/quest", &encrypted).unwrap();
+        let unpacked: Vec<_> = files
+            .iter()
+            .filter(|(path, _)| !path.ends_with(PACK_MARKER_FILE))
+            .collect();
+        assert_eq!(unpacked.len(), 2);
+
+        let root = manifest.root;
+        assert_eq!(
+            root.layers,
+            vec![Layer::Ecd {
+                index: 4,
+                version: 31739
+            }]
+        );
+        let NodeContent::SimpleArchive {
+            alignment, entries, ..
+        } = root.content
+        else {
+            panic!("root should be a simple archive");
+        };
+        assert_eq!(alignment, 4);
+        assert!(entries[0].layers.is_empty());
+        assert_eq!(
+            entries[0].content,
+            NodeContent::File {
+                path: "quest/0000.bin".to_string()
             }
+        );
+        assert_eq!(entries[1].layers, vec![Layer::Jpk { jpk_type: 3 }]);
+    }
+
+    #[test]
+    fn repack_from_manifest_roundtrip() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let inner = encode_simple_archive_aligned(&[vec![7; 5], vec![8; 3]], 1).unwrap();
+        let archive = encode_simple_archive(&[
+            create_jpk(&uncomp, 3).unwrap(),
+            create_jpk(&inner, 0).unwrap(),
+            vec![1, 2, 3, 4],
+        ])
+        .unwrap();
+        let params = EcdParams {
+            index: 1,
+            version: 100,
+        };
+        let encrypted = encrypt_ecd_with(&archive, params).unwrap();
+
+        let out_dir = Path::new("tests/data/out/repack_roundtrip");
+        let prefix = out_dir.join("quest");
+        let (files, manifest) =
+            unpack_buffer_with_manifest(prefix.to_str().unwrap(), &encrypted).unwrap();
+        for (path, buf) in &files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        let repacked = repack_from_manifest(&manifest, out_dir).unwrap();
+        assert!(repacked == encrypted, "the buffers are not equal");
+    }
+
+    #[test]
+    fn exf_layer_roundtrip() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let params = ExfParams {
+            index: 2,
+            version: 7,
+        };
+        let encrypted =
+            pack_buffer(&create_jpk(&uncomp, 0).unwrap(), PackType::Exf(params)).unwrap();
+
+        let out_dir = Path::new("tests/data/out/exf_roundtrip");
+        let prefix = out_dir.join("quest");
+        let (files, manifest) =
+            unpack_buffer_with_manifest(prefix.to_str().unwrap(), &encrypted).unwrap();
+        assert_eq!(
+            manifest.root.layers,
+            vec![
+                Layer::Exf {
+                    index: 2,
+                    version: 7
+                },
+                Layer::Jpk { jpk_type: 0 }
+            ]
+        );
+        assert_eq!(files[0].1, uncomp);
+        for (path, buf) in &files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        let repacked = repack_from_manifest(&manifest, out_dir).unwrap();
+        assert!(repacked == encrypted, "the buffers are not equal");
+    }
+
+    #[test]
+    fn mha_entry_fields_survive_repack() {
+        let entries = vec![
+            MhaEntry::new("b.bin".to_string(), 40, vec![1, 2, 3, 4]),
+            MhaEntry {
+                name: "a.bin".to_string(),
+                id: 7,
+                size: 2,
+                padded_size: 8,
+                data: vec![5, 6],
+            },
+        ];
+        let archive = encode_mha_archive(&entries, 7, 64).unwrap();
+
+        let out_dir = Path::new("tests/data/out/mha_fields");
+        let prefix = out_dir.join("archive");
+        let (files, manifest) =
+            unpack_buffer_with_manifest(prefix.to_str().unwrap(), &archive).unwrap();
+        let (metadata_path, metadata_buf) = files
+            .iter()
+            .find(|(path, _)| path.ends_with(MHA_METADATA_FILE))
+            .unwrap();
+        assert_eq!(metadata_path, &prefix.join(MHA_METADATA_FILE));
+        let metadata = MhaMetadata::from_json(std::str::from_utf8(metadata_buf).unwrap()).unwrap();
+        assert_eq!((metadata.base_id, metadata.capacity), (7, 64));
+        assert_eq!(metadata.entries[0].id, 40);
+        assert_eq!(metadata.entries[1].padded_size, 8);
+
+        for (path, buf) in &files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+        assert_eq!(repack_from_manifest(&manifest, out_dir).unwrap(), archive);
+    }
+
+    #[test]
+    fn nested_mha_folder_is_packed_from_metadata() {
+        // PNG data, so packing doesn't JPK compress the files like it does for .bin
+        let png = |byte| vec![0x89, b'P', b'N', b'G', byte, 0, 0, 0];
+        let entries = vec![
+            MhaEntry::new("b.tex".to_string(), 40, png(1)),
+            MhaEntry::new("a.tex".to_string(), 12, png(2)),
+        ];
+        let mha = encode_mha_archive(&entries, 12, 64).unwrap();
+        let archive = encode_simple_archive(&[mha.clone(), png(3)]).unwrap();
+
+        let out_dir = Path::new("tests/data/out/mha_metadata");
+        let _ = fs::remove_dir_all(out_dir);
+        let prefix = out_dir.join("archive");
+        for (path, buf) in unpack_buffer(prefix.to_str().unwrap(), &archive).unwrap() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        assert_eq!(
+            pack_folder(&prefix, FolderPackType::Simple).unwrap(),
+            archive
+        );
+        assert_eq!(
+            pack_folder(&prefix.join("0000"), FolderPackType::MHA(12, 64)).unwrap(),
+            mha
+        );
+
+        // Files added after unpacking get the next free ID
+        fs::write(prefix.join("0000").join("c.png"), png(4)).unwrap();
+        let repacked = pack_folder(&prefix.join("0000"), FolderPackType::MHA(12, 64)).unwrap();
+        let ids: Vec<_> = decode_mha_archive(&repacked)
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec![40, 12, 41]);
+
+        // Unless the highest ID leaves no room
+        let metadata_path = prefix.join("0000").join(MHA_METADATA_FILE);
+        let mut metadata =
+            MhaMetadata::parse(&fs::read_to_string(&metadata_path).unwrap()).unwrap();
+        metadata.entries[0].id = u32::MAX;
+        fs::write(&metadata_path, metadata.to_json().unwrap()).unwrap();
+        let overflow = pack_folder(&prefix.join("0000"), FolderPackType::MHA(12, 64));
+        assert!(matches!(
+            overflow,
+            Err(Error::Layer { source, .. }) if matches!(*source, Error::Manifest(_))
+        ));
+    }
+
+    #[test]
+    fn mha_entries_sharing_a_stem_are_kept_apart() {
+        let png = |byte| vec![0x89, b'P', b'N', b'G', byte, 0, 0, 0];
+        let entries = vec![
+            MhaEntry::new("a.tex".to_string(), 12, png(1)),
+            MhaEntry::new("a.dat".to_string(), 13, png(2)),
+            MhaEntry::new("A".to_string(), 14, png(3)),
+            MhaEntry::new("b.tex".to_string(), 15, png(4)),
+        ];
+        let mha = encode_mha_archive(&entries, 12, 64).unwrap();
+
+        let out_dir = Path::new("tests/data/out/mha_stems");
+        let _ = fs::remove_dir_all(out_dir);
+        let prefix = out_dir.join("archive");
+        let files = unpack_buffer(prefix.to_str().unwrap(), &mha).unwrap();
+        let mut names: Vec<_> = files
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_lowercase())
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), files.len());
+        for (path, buf) in files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        assert_eq!(
+            pack_folder(&prefix, FolderPackType::MHA(12, 64)).unwrap(),
+            mha
+        );
+    }
+
+    #[test]
+    fn nested_layers_are_packed_from_markers() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        // Unpadded, so the archives are detected whatever the size of their entries
+        let inner = encode_simple_archive_aligned(&[vec![7; 8], uncomp.clone()], 1).unwrap();
+        let mha = encode_mha_archive(
+            &[MhaEntry::new(
+                "a.bin".to_string(),
+                3,
+                ecd::encrypt_ecd(&uncomp).unwrap(),
+            )],
+            3,
+            8,
+        )
+        .unwrap();
+        let archive = encode_simple_archive_aligned(
+            &[
+                create_jpk(&inner, 0).unwrap(),
+                encrypt_exf_with(&mha, ExfParams::default()).unwrap(),
+                create_jpk(&uncomp, 3).unwrap(),
+                uncomp.clone(),
+            ],
+            1,
+        )
+        .unwrap();
+        let encrypted = ecd::encrypt_ecd(&archive).unwrap();
+
+        let out_dir = Path::new("tests/data/out/pack_markers");
+        let _ = fs::remove_dir_all(out_dir);
+        let prefix = out_dir.join("quest");
+        for (path, buf) in unpack_buffer(prefix.to_str().unwrap(), &encrypted).unwrap() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        let repacked = pack_folder(&prefix, FolderPackType::Simple).unwrap();
+        assert!(repacked == encrypted, "the buffers are not equal");
+    }
+
+    #[test]
+    fn lenient_crc_unpack_reports_warning() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let mut encrypted = ecd::encrypt_ecd(&uncomp).unwrap();
+        encrypted[200] ^= 1;
+
+        assert!(matches!(
+            unpack_buffer("out/quest", &encrypted),
+            Err(Error::Layer { .. })
+        ));
+
+        let options = UnpackOptions {
+            lenient_crc: true,
+            ..Default::default()
+        };
+        let output = unpack_buffer_with_options("out/quest", &encrypted, &options).unwrap();
+        assert_eq!(output.files.len(), 1);
+        assert_eq!(output.warnings.len(), 1);
+    }
+
+    #[test]
+    fn simple_archive_detection_is_configurable() {
+        let inner = encode_simple_archive(&[vec![1, 2, 3], vec![4, 5]]).unwrap();
+        let outer = encode_simple_archive(&[vec![9; 7], inner]).unwrap();
+        let file_count = |options: &UnpackOptions| {
+            let output = unpack_buffer_with_options("out/quest", &outer, options).unwrap();
+            output
+                .files
+                .iter()
+                .filter(|(path, _)| !path.ends_with(PACK_MARKER_FILE))
+                .count()
+        };
+
+        // Padded entries don't prevent detection
+        assert_eq!(file_count(&UnpackOptions::default()), 3);
+
+        let mut options = UnpackOptions::default();
+        options
+            .simple_archive_overrides
+            .insert("quest/0001.bin".to_string(), false);
+        assert_eq!(file_count(&options), 2);
+
+        let options = UnpackOptions {
+            simple_archive_threshold: 1.1,
+            ..Default::default()
+        };
+        assert_eq!(file_count(&options), 1);
+    }
+
+    #[test]
+    fn irregular_simple_archive_is_repacked() {
+        // Entries 0 and 1 share data, entry 2 is empty and the archive ends with odd padding
+        let mut archive = vec![3, 0, 0, 0];
+        for (offset, size) in [(28u32, 8u32), (28, 8), (37, 0)] {
+            archive.extend_from_slice(&offset.to_le_bytes());
+            archive.extend_from_slice(&size.to_le_bytes());
+        }
+        archive.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+        archive.extend_from_slice(&[0; 3]);
+        let encrypted = ecd::encrypt_ecd(&archive).unwrap();
+
+        let out_dir = Path::new("tests/data/out/irregular_archive");
+        let _ = fs::remove_dir_all(out_dir);
+        let prefix = out_dir.join("archive");
+        let (files, manifest) =
+            unpack_buffer_with_manifest(prefix.to_str().unwrap(), &encrypted).unwrap();
+        for (path, buf) in files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        assert_eq!(repack_from_manifest(&manifest, out_dir).unwrap(), encrypted);
+        assert_eq!(
+            pack_folder(&prefix, FolderPackType::Simple).unwrap(),
+            encrypted
+        );
+    }
+
+    #[test]
+    fn thread_count_does_not_change_output() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let entries: Vec<_> = (0..12u8)
+            .map(|i| {
+                let mut data = uncomp.clone();
+                data[0] = i;
+                create_jpk(&data, 3).unwrap()
+            })
+            .collect();
+        let inner = encode_simple_archive_aligned(&entries[..4], 1).unwrap();
+        let mut outer = entries[4..].to_vec();
+        outer.insert(2, create_jpk(&inner, 0).unwrap());
+        let archive = encode_simple_archive_aligned(&outer, 1).unwrap();
+
+        let out_dir = Path::new("tests/data/out/thread_count");
+        let _ = fs::remove_dir_all(out_dir);
+        let prefix = out_dir.join("archive");
+        let unpack = |jobs| {
+            let options = UnpackOptions {
+                jobs: Some(jobs),
+                ..Default::default()
+            };
+            unpack_buffer_with_options(prefix.to_str().unwrap(), &archive, &options).unwrap()
+        };
+        let sequential = unpack(1);
+        let parallel = unpack(4);
+        assert_eq!(parallel.files, sequential.files);
+        assert_eq!(
+            parallel.manifest.to_json().unwrap(),
+            sequential.manifest.to_json().unwrap()
+        );
 
+        for (path, buf) in sequential.files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
             fs::write(path, buf).unwrap();
         }
-        let repacked_em_archive = pack_em_folder(test_path);
-        let repacked_files = unpack_buffer(test_path.to_str().unwrap(), &repacked_em_archive);
-        assert_eq!(unpacked_files.len(), repacked_files.len());
-        for (i, (path, buf)) in unpacked_files.iter().enumerate() {
-            let (repacked_path, repacked_buf) = &repacked_files[i];
-            assert_eq!(path, repacked_path);
-            assert_eq!(buf, repacked_buf);
+        for jobs in [1, 4] {
+            let options = PackOptions {
+                jobs: Some(jobs),
+                ..Default::default()
+            };
+            let repacked =
+                pack_folder_with_options(&prefix, FolderPackType::Simple, &options).unwrap();
+            assert_eq!(repacked, archive);
+            let repacked =
+                repack_from_manifest_with_options(&parallel.manifest, out_dir, &options).unwrap();
+            assert_eq!(repacked, archive);
         }
     }
 }
@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+pub const MANIFEST_VERSION: u32 = 1;
+pub const MANIFEST_EXTENSION: &str = "rsfrontier.json";
+/// Name of the marker written into every folder unpacked from an archive.
+pub const PACK_MARKER_FILE: &str = ".pack.json";
+
+/// Describes how an unpacked file or folder was stored in the original buffer,
+/// so it can be rebuilt without guessing from file extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub root: ManifestNode,
+}
+
+/// A wrapping layer around a node, listed outermost first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Layer {
+    Ecd { index: u16, version: u16 },
+    Exf { index: u16, version: u16 },
+    Jpk { jpk_type: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestNode {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+    #[serde(flatten)]
+    pub content: NodeContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NodeContent {
+    /// A leaf written to disk, `path` is relative to the manifest's folder and uses '/'.
+    File { path: String },
+    SimpleArchive {
+        alignment: u32,
+        /// Exact entry offsets and archive size, see `SimpleArchiveLayout`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offsets: Option<Vec<u32>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u32>,
+        entries: Vec<ManifestNode>,
+    },
+    Mha {
+        base_id: u16,
+        capacity: u16,
+        entries: Vec<MhaManifestEntry>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MhaManifestEntry {
+    pub name: String,
+    /// Entry fields to write back, unset in manifests from before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padded_size: Option<u32>,
+    #[serde(flatten)]
+    pub node: ManifestNode,
+}
+
+/// Tells `pack_folder` which layers to re-apply to a folder and the files directly inside it.
+/// Unlike a `Manifest`, files can be added, renamed or removed from the folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackMarker {
+    /// Layers around the archive built from the folder, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+    /// Entry alignment of a Simple Archive folder, `DEFAULT_ALIGNMENT` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alignment: Option<u32>,
+    /// Exact entry offsets and archive size, see `SimpleArchiveLayout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offsets: Option<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    /// Layers of the files in the folder, by file name. Files that aren't listed are stored as is.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, Vec<Layer>>,
+}
+
+impl PackMarker {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| Error::Manifest(e.to_string()))
+    }
+}
+
+impl Manifest {
+    pub fn new(root: ManifestNode) -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            root,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let manifest: Manifest =
+            serde_json::from_str(json).map_err(|e| Error::Manifest(e.to_string()))?;
+
+        if manifest.version != MANIFEST_VERSION {
+            return Err(Error::Manifest(format!(
+                "unsupported manifest version {}",
+                manifest.version
+            )));
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// Path of the manifest written alongside an unpack output, e.g. `out/mhfdat` -> `out/mhfdat.rsfrontier.json`.
+pub fn manifest_path(unpack_path: &Path) -> PathBuf {
+    let mut file_name = unpack_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(MANIFEST_EXTENSION);
+    unpack_path.with_file_name(file_name)
+}
+
+/// Converts an on-disk path to the '/' separated form stored in manifests.
+pub(crate) fn to_manifest_path(path: &Path, root: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        Layer, Manifest, ManifestNode, MhaManifestEntry, NodeContent, manifest_path,
+        to_manifest_path,
+    };
+
+    #[test]
+    fn manifest_json_roundtrip() {
+        let manifest = Manifest::new(ManifestNode {
+            layers: vec![Layer::Ecd {
+                index: 4,
+                version: 31739,
+            }],
+            content: NodeContent::Mha {
+                base_id: 500,
+                capacity: 500,
+                entries: vec![MhaManifestEntry {
+                    name: "a.bin".to_string(),
+                    id: Some(500),
+                    size: Some(12),
+                    padded_size: Some(16),
+                    node: ManifestNode {
+                        layers: vec![Layer::Jpk { jpk_type: 3 }],
+                        content: NodeContent::File {
+                            path: "out/a.bin".to_string(),
+                        },
+                    },
+                }],
+            },
+        });
+
+        let json = manifest.to_json().unwrap();
+        assert_eq!(Manifest::from_json(&json).unwrap(), manifest);
+    }
+
+    #[test]
+    fn manifest_paths() {
+        assert_eq!(
+            manifest_path(Path::new("out/mhfdat")),
+            Path::new("out/mhfdat.rsfrontier.json")
+        );
+        assert_eq!(
+            to_manifest_path(Path::new("out/mhfdat/0001.bin"), Path::new("out")),
+            "mhfdat/0001.bin"
+        );
+    }
+}
@@ -1,6 +1,96 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, WriteBytesExt};
 use std::io::{Cursor, Seek, Write};
-use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Format, ReadFormatExt, Result, get_slice},
+    text::{decode_sjis, encode_sjis},
+};
+
+/// Name of the file written next to the entries of an unpacked MHA archive.
+pub const MHA_METADATA_FILE: &str = ".metadata";
+
+/// An archive entry with every field of its 20 byte record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MhaEntry {
+    /// Converted with `decode_name`/`encode_name`.
+    pub name: String,
+    pub id: u32,
+    /// Size recorded in the archive, `encode_mha_archive` writes `data.len()` instead.
+    pub size: u32,
+    /// The second size field, usually equal to `size`. When larger, the entry's data is
+    /// followed by zeros up to this size.
+    pub padded_size: u32,
+    pub data: Vec<u8>,
+}
+
+impl MhaEntry {
+    /// An entry whose sizes both match `data`.
+    pub fn new(name: String, id: u32, data: Vec<u8>) -> Self {
+        let size = data.len() as u32;
+        MhaEntry {
+            name,
+            id,
+            size,
+            padded_size: size,
+            data,
+        }
+    }
+
+    /// The second size field to write, kept as read unless the data changed size.
+    fn padded_size_for_data(&self) -> u32 {
+        let data_len = self.data.len() as u32;
+        if self.padded_size == self.size {
+            data_len
+        } else {
+            self.padded_size.max(data_len)
+        }
+    }
+}
+
+/// Header and entry fields of an unpacked archive, written as JSON to `MHA_METADATA_FILE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MhaMetadata {
+    pub base_id: u16,
+    pub capacity: u16,
+    pub entries: Vec<MhaEntryMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MhaEntryMetadata {
+    /// Name stored in the archive.
+    pub name: String,
+    /// Name of the unpacked file or folder, which may have another extension.
+    pub file: String,
+    pub id: u32,
+    pub size: u32,
+    pub padded_size: u32,
+}
+
+impl MhaMetadata {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| Error::Manifest(e.to_string()))
+    }
+
+    /// Reads a metadata file, either JSON or the `base_id,capacity` text written by older versions.
+    pub fn parse(text: &str) -> Result<Self> {
+        if let Some((base_id, capacity)) = text.trim().split_once(',')
+            && let (Ok(base_id), Ok(capacity)) = (base_id.trim().parse(), capacity.trim().parse())
+        {
+            return Ok(MhaMetadata {
+                base_id,
+                capacity,
+                entries: Vec::new(),
+            });
+        }
+        Self::from_json(text)
+    }
+}
 
 pub fn is_buf_mha(buf: &[u8]) -> bool {
     let magic: u32 = u32::from_le_bytes(
@@ -12,48 +102,150 @@ pub fn is_buf_mha(buf: &[u8]) -> bool {
     magic == 23160941
 }
 
-fn read_null_terminated_string(buf: &[u8], offset: usize) -> &str {
-    let sub_slice = &buf[offset..];
-    let next_null = sub_slice.iter().position(|&byte| byte == 0).unwrap();
-    let string_slice = &sub_slice[..next_null];
-    std::str::from_utf8(string_slice).unwrap()
+fn read_null_terminated_string(buf: &[u8], offset: usize) -> Result<&[u8]> {
+    let sub_slice = buf.get(offset..).ok_or(Error::Truncated {
+        format: Format::Mha,
+        offset: buf.len(),
+    })?;
+    let next_null = sub_slice
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(Error::Truncated {
+            format: Format::Mha,
+            offset: buf.len(),
+        })?;
+    Ok(&sub_slice[..next_null])
+}
+
+/// Characters that can't appear in a file name on some platforms, they are percent-escaped.
+fn needs_escape(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '%' | '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|'
+        )
+}
+
+fn push_escaped(out: &mut String, byte: u8) {
+    out.push_str(&format!("%{:02X}", byte));
+}
+
+/// Turns a raw Shift-JIS entry name into a name usable on disk. Bytes that aren't valid
+/// Shift-JIS, characters that aren't allowed in file names, '%' and a leading '.'
+/// (which would hide the file from `pack`) are written as `%XX`. `encode_name` reverses it.
+pub fn decode_name(raw: &[u8]) -> String {
+    let mut out = String::new();
+
+    // Only names that encode back to the same bytes are decoded, the rest is escaped byte by byte
+    match decode_sjis(raw).filter(|name| encode_sjis(name).as_deref() == Some(raw)) {
+        Some(name) => {
+            for (i, c) in name.chars().enumerate() {
+                if needs_escape(c) || (i == 0 && c == '.') {
+                    push_escaped(&mut out, c as u8);
+                } else {
+                    out.push(c);
+                }
+            }
+        }
+        None => {
+            for (i, &byte) in raw.iter().enumerate() {
+                let c = byte as char;
+                if byte.is_ascii() && !needs_escape(c) && !(i == 0 && c == '.') {
+                    out.push(c);
+                } else {
+                    push_escaped(&mut out, byte);
+                }
+            }
+        }
+    }
+
+    out
 }
 
-pub fn decode_mha_archive(buf: &[u8]) -> Vec<(&str, Vec<u8>)> {
+/// Converts a name produced by `decode_name` (or typed by hand) back to the bytes stored
+/// in the archive.
+pub fn encode_name(name: &str) -> Result<Vec<u8>> {
     let mut out = Vec::new();
+    let mut rest = name;
+
+    while !rest.is_empty() {
+        let escape = rest
+            .strip_prefix('%')
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = escape {
+            out.push(byte);
+            rest = &rest[3..];
+            continue;
+        }
+
+        let len = rest
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == '%')
+            .map_or(rest.len(), |(i, _)| i);
+        let bytes = encode_sjis(&rest[..len]).ok_or_else(|| {
+            Error::Text(format!(
+                "file name '{}' can't be encoded as Shift-JIS",
+                name
+            ))
+        })?;
+        out.extend_from_slice(&bytes);
+        rest = &rest[len..];
+    }
+
+    Ok(out)
+}
+
+/// Reads the `(base_id, capacity)` pair stored at the end of the MHA header.
+pub fn read_header_ids(buf: &[u8]) -> Result<(u16, u16)> {
     let mut cursor = Cursor::new(buf);
+    cursor.set_position(20);
+    let base_id = cursor.read_u16_for(Format::Mha)?;
+    let capacity = cursor.read_u16_for(Format::Mha)?;
+    Ok((base_id, capacity))
+}
 
-    let _ = cursor.read_u32::<LittleEndian>().unwrap();
-    let metadata_addy = cursor.read_u32::<LittleEndian>().unwrap();
-    let file_count = cursor.read_u32::<LittleEndian>().unwrap();
-    let string_start = cursor.read_u32::<LittleEndian>().unwrap();
-    let _ = cursor.read_u32::<LittleEndian>().unwrap();
-    let base_id = cursor.read_u16::<LittleEndian>().unwrap();
-    let capacity = cursor.read_u16::<LittleEndian>().unwrap();
+/// Entry names are converted with `decode_name`.
+pub fn decode_mha_archive(buf: &[u8]) -> Result<Vec<MhaEntry>> {
+    let mut out = Vec::new();
+    let mut cursor = Cursor::new(buf);
+
+    let _ = cursor.read_u32_for(Format::Mha)?;
+    let metadata_addy = cursor.read_u32_for(Format::Mha)?;
+    let file_count = cursor.read_u32_for(Format::Mha)?;
+    let string_start = cursor.read_u32_for(Format::Mha)?;
 
     for i in 0..file_count {
-        let meta_data_start = (metadata_addy + (i * 20)) as u64;
-        cursor
-            .seek(std::io::SeekFrom::Start(meta_data_start))
-            .unwrap();
+        let meta_data_start = metadata_addy as u64 + (i as u64 * 20);
+        cursor.seek(std::io::SeekFrom::Start(meta_data_start))?;
 
-        let file_name_off = cursor.read_u32::<LittleEndian>().unwrap();
-        let file_data_off = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let file_size = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        let file_name_off = cursor.read_u32_for(Format::Mha)?;
+        let file_data_off = cursor.read_u32_for(Format::Mha)? as usize;
+        let file_size = cursor.read_u32_for(Format::Mha)?;
+        let padded_size = cursor.read_u32_for(Format::Mha)?;
+        let id = cursor.read_u32_for(Format::Mha)?;
 
-        let file_name = read_null_terminated_string(buf, (string_start + file_name_off) as usize);
-        let file_data = buf[file_data_off..file_data_off + file_size].to_vec();
+        let file_name = decode_name(read_null_terminated_string(
+            buf,
+            string_start as usize + file_name_off as usize,
+        )?);
+        let file_data = get_slice(buf, file_data_off, file_size as usize, Format::Mha)?.to_vec();
 
-        out.push((file_name, file_data));
+        out.push(MhaEntry {
+            name: file_name,
+            id,
+            size: file_size,
+            padded_size,
+            data: file_data,
+        });
     }
 
-    let metadata_filebuf = format!("{},{}", base_id, capacity).as_bytes().to_vec();
-    out.push((".metadata", metadata_filebuf));
-
-    out
+    Ok(out)
 }
 
-pub fn encode_mha_archive(files: Vec<(String, Vec<u8>)>, base_id: u16, capacity: u16) -> Vec<u8> {
+/// Entry names are converted with `encode_name`.
+pub fn encode_mha_archive(entries: &[MhaEntry], base_id: u16, capacity: u16) -> Result<Vec<u8>> {
     let mut out = Vec::new();
 
     let header_size = 24_usize;
@@ -61,31 +253,25 @@ pub fn encode_mha_archive(files: Vec<(String, Vec<u8>)>, base_id: u16, capacity:
     let mut string_buf: Vec<u8> = Vec::new();
     let mut metadata_buf: Vec<u8> = Vec::new();
 
-    let nb_files = files.len();
+    let nb_files = entries.len();
 
-    for (_, file_buf) in &files {
-        let _ = files_buf.write(file_buf);
-    }
+    for entry in entries {
+        let _ = metadata_buf.write_u32::<LittleEndian>(string_buf.len() as u32);
+        let _ = metadata_buf.write_u32::<LittleEndian>((header_size + files_buf.len()) as u32);
+        let padded_size = entry.padded_size_for_data();
+        let _ = metadata_buf.write_u32::<LittleEndian>(entry.data.len() as u32);
+        let _ = metadata_buf.write_u32::<LittleEndian>(padded_size);
+        let _ = metadata_buf.write_u32::<LittleEndian>(entry.id);
 
-    for (file_name, _) in &files {
-        let string_bytes = file_name.as_bytes();
-        let _ = string_buf.write(string_bytes);
+        let _ = files_buf.write(&entry.data);
+        files_buf.resize(
+            files_buf.len() + (padded_size as usize - entry.data.len()),
+            0,
+        );
+        let _ = string_buf.write(&encode_name(&entry.name)?);
         string_buf.push(0);
     }
 
-    let mut relative_str_off = 0_usize;
-    let mut relative_file_data_off = 0_usize;
-    for (file_count, (file_name, file_buf)) in files.into_iter().enumerate() {
-        let _ = metadata_buf.write_u32::<LittleEndian>(relative_str_off as u32);
-        relative_str_off += file_name.len() + 1;
-        let _ =
-            metadata_buf.write_u32::<LittleEndian>((header_size + relative_file_data_off) as u32);
-        relative_file_data_off += file_buf.len();
-        let _ = metadata_buf.write_u32::<LittleEndian>(file_buf.len() as u32);
-        let _ = metadata_buf.write_u32::<LittleEndian>(file_buf.len() as u32);
-        let _ = metadata_buf.write_u32::<LittleEndian>((base_id as u32) + (file_count as u32));
-    }
-
     let _ = out.write_u32::<LittleEndian>(23160941);
     let _ =
         out.write_u32::<LittleEndian>((header_size + files_buf.len() + string_buf.len()) as u32);
@@ -98,5 +284,149 @@ pub fn encode_mha_archive(files: Vec<(String, Vec<u8>)>, base_id: u16, capacity:
     let _ = out.write(&string_buf);
     let _ = out.write(&metadata_buf);
 
-    out
+    if out.len() > u32::MAX as usize {
+        return Err(Error::TooLarge {
+            format: Format::Mha,
+            size: out.len(),
+        });
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::text::encode_sjis;
+
+    use super::{
+        MhaEntry, MhaEntryMetadata, MhaMetadata, decode_mha_archive, decode_name,
+        encode_mha_archive, encode_name, read_header_ids,
+    };
+
+    #[test]
+    fn mha_roundtrip() {
+        let entries = vec![
+            MhaEntry::new("a.bin".to_string(), 500, vec![1, 2, 3]),
+            MhaEntry::new("b.dds".to_string(), 501, vec![4, 5, 6, 7, 8]),
+        ];
+        let encoded = encode_mha_archive(&entries, 500, 500).unwrap();
+
+        assert_eq!(decode_mha_archive(&encoded).unwrap(), entries);
+        assert_eq!(read_header_ids(&encoded).unwrap(), (500, 500));
+    }
+
+    #[test]
+    fn entry_fields_are_preserved() {
+        let entries = vec![
+            MhaEntry {
+                name: "a.bin".to_string(),
+                id: 12,
+                size: 3,
+                padded_size: 16,
+                data: vec![1, 2, 3],
+            },
+            MhaEntry::new("b.bin".to_string(), 7, vec![4]),
+            MhaEntry::new("c.bin".to_string(), 900, vec![]),
+        ];
+        let encoded = encode_mha_archive(&entries, 7, 10).unwrap();
+        let decoded = decode_mha_archive(&encoded).unwrap();
+        assert_eq!(decoded, entries);
+        assert_eq!(encode_mha_archive(&decoded, 7, 10).unwrap(), encoded);
+
+        // Resized data keeps the padded size unless it no longer fits
+        let mut grown = decoded.clone();
+        grown[0].data = vec![0; 20];
+        grown[1].data = vec![0; 2];
+        let regrown = decode_mha_archive(&encode_mha_archive(&grown, 7, 10).unwrap()).unwrap();
+        assert_eq!((regrown[0].size, regrown[0].padded_size), (20, 20));
+        assert_eq!((regrown[1].size, regrown[1].padded_size), (2, 2));
+    }
+
+    #[test]
+    fn padded_data_is_reproduced() {
+        // Built by hand: "a.bin" holds 3 bytes padded to 8, "b.bin" 2 unpadded bytes
+        let mut archive = Vec::new();
+        for value in [23160941u32, 24 + 10 + 12, 2, 24 + 10, 12] {
+            archive.extend_from_slice(&value.to_le_bytes());
+        }
+        archive.extend_from_slice(&[5, 0, 2, 0]);
+        archive.extend_from_slice(&[1, 2, 3, 0, 0, 0, 0, 0, 4, 5]);
+        archive.extend_from_slice(b"a.bin\0b.bin\0");
+        for value in [0u32, 24, 3, 8, 5, 6, 32, 2, 2, 6] {
+            archive.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let decoded = decode_mha_archive(&archive).unwrap();
+        assert_eq!(decoded[0].data, vec![1, 2, 3]);
+        assert_eq!(decoded[0].padded_size, 8);
+        assert_eq!(decoded[1].data, vec![4, 5]);
+        assert_eq!(encode_mha_archive(&decoded, 5, 2).unwrap(), archive);
+    }
+
+    #[test]
+    fn metadata_formats_are_parsed() {
+        let legacy = MhaMetadata::parse("500,32\n").unwrap();
+        assert_eq!((legacy.base_id, legacy.capacity), (500, 32));
+        assert!(legacy.entries.is_empty());
+
+        let metadata = MhaMetadata {
+            base_id: 1,
+            capacity: 2,
+            entries: vec![MhaEntryMetadata {
+                name: "a.tex".to_string(),
+                file: "a.dds".to_string(),
+                id: 3,
+                size: 4,
+                padded_size: 5,
+            }],
+        };
+        assert_eq!(
+            MhaMetadata::parse(&metadata.to_json().unwrap()).unwrap(),
+            metadata
+        );
+        assert!(MhaMetadata::parse("500").is_err());
+    }
+
+    #[test]
+    fn truncated_mha_is_an_error() {
+        let entries = vec![MhaEntry::new("a.bin".to_string(), 0, vec![1, 2, 3])];
+        let encoded = encode_mha_archive(&entries, 500, 500).unwrap();
+
+        assert!(decode_mha_archive(&encoded[..20]).is_err());
+        assert!(decode_mha_archive(&encoded[..encoded.len() - 12]).is_err());
+    }
+
+    #[test]
+    fn japanese_names_roundtrip() {
+        let entries = vec![MhaEntry::new(
+            "武器データ.bin".to_string(),
+            0,
+            vec![1, 2, 3],
+        )];
+        let encoded = encode_mha_archive(&entries, 0, 1).unwrap();
+        let raw_name = encode_sjis("武器データ.bin").unwrap();
+        assert!(encoded.windows(raw_name.len()).any(|w| w == raw_name));
+
+        let decoded = decode_mha_archive(&encoded).unwrap();
+        assert_eq!(decoded, entries);
+        assert_eq!(encode_mha_archive(&decoded, 0, 1).unwrap(), encoded);
+    }
+
+    #[test]
+    fn undecodable_names_are_escaped() {
+        let raws: [&[u8]; 4] = [b"a/b%c.bin", b".hidden", &[0x82, 0xA0, 0x81], &[0xFF, b'x']];
+        for raw in raws {
+            let name = decode_name(raw);
+            assert!(!name.contains('/'));
+            assert!(!name.starts_with('.'));
+            assert_eq!(encode_name(&name).unwrap(), raw);
+        }
+        assert_eq!(decode_name(b"a/b%c.bin"), "a%2Fb%25c.bin");
+        assert_eq!(decode_name(&[0xFF, b'x']), "%FFx");
+
+        // Names typed by hand may hold a '%' that isn't an escape
+        let mut expected = encode_sjis("武").unwrap();
+        expected.extend_from_slice(b"%z");
+        assert_eq!(encode_name("武%z").unwrap(), expected);
+    }
 }
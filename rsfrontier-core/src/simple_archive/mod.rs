@@ -1,90 +1,259 @@
 use std::io::{Cursor, Write};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::error::{Error, Format, ReadFormatExt, Result, get_slice};
+
+pub const DEFAULT_ALIGNMENT: u32 = 4;
+
+// Alignments tried, in order, when guessing how an existing archive was laid out
+const CANDIDATE_ALIGNMENTS: [u32; 6] = [DEFAULT_ALIGNMENT, 1, 2, 8, 16, 32];
 
 fn align_up(value: u32, alignment: u32) -> u32 {
     (value + alignment - 1) & !(alignment - 1)
 }
 
-pub fn decode_simple_archive(buf: &[u8]) -> Vec<Vec<u8>> {
+/// Reads the `(offset, size)` of every entry.
+pub(crate) fn read_entry_table(buf: &[u8]) -> Result<Vec<(usize, usize)>> {
     let mut out = Vec::new();
     let mut cursor = Cursor::new(buf);
 
-    let file_count = cursor.read_u32::<LittleEndian>().unwrap();
+    let file_count = cursor.read_u32_for(Format::SimpleArchive)?;
 
     for _ in 0..file_count {
-        let file_offset = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let file_size = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-
-        let file_buf = buf[file_offset..file_offset + file_size].to_vec();
-        out.push(file_buf);
+        let file_offset = cursor.read_u32_for(Format::SimpleArchive)? as usize;
+        let file_size = cursor.read_u32_for(Format::SimpleArchive)? as usize;
+        out.push((file_offset, file_size));
     }
 
-    out
+    Ok(out)
 }
 
-pub fn encode_simple_archive(files: &[Vec<u8>]) -> Vec<u8> {
-    let out = Vec::new();
-    let mut cursor = Cursor::new(out);
+pub fn decode_simple_archive(buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    read_entry_table(buf)?
+        .into_iter()
+        .map(|(file_offset, file_size)| {
+            Ok(get_slice(buf, file_offset, file_size, Format::SimpleArchive)?.to_vec())
+        })
+        .collect()
+}
 
-    let file_count = files.len();
-    cursor.write_u32::<LittleEndian>(file_count as u32).unwrap();
+/// Where the entries of an archive were placed, so re-encoding it gives back the same bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimpleArchiveLayout {
+    /// Entry data is padded to a multiple of this.
+    pub alignment: u32,
+    /// Original offset of every entry, only set when `alignment` doesn't explain them
+    /// (irregular padding, entries sharing data...).
+    pub offsets: Option<Vec<u32>>,
+    /// Original archive size, keeps irregular padding after the last entry.
+    pub size: Option<u32>,
+}
 
-    let mut file_start_off = (4 + file_count * 8) as u32;
-    const ALIGNMENT: u32 = 4;
+impl SimpleArchiveLayout {
+    pub fn aligned(alignment: u32) -> Self {
+        SimpleArchiveLayout {
+            alignment,
+            offsets: None,
+            size: None,
+        }
+    }
+}
 
-    for file in files {
-        let buf_size = file.len();
-        cursor.write_u32::<LittleEndian>(file_start_off).unwrap();
-        cursor.write_u32::<LittleEndian>(buf_size as u32).unwrap();
+/// Returns the alignment the archive's entries were padded to, falling back to
+/// `DEFAULT_ALIGNMENT` when the layout doesn't match any of the usual ones.
+pub fn detect_alignment(buf: &[u8]) -> Result<u32> {
+    detect_layout(buf).map(|layout| layout.alignment)
+}
 
-        let padded_size = align_up(buf_size as u32, ALIGNMENT);
-        file_start_off += padded_size;
+/// Returns the layout of the archive's entries, with their exact offsets when
+/// no single alignment reproduces them.
+pub fn detect_layout(buf: &[u8]) -> Result<SimpleArchiveLayout> {
+    let entries = read_entry_table(buf)?;
+    if let Some(alignment) = detect_layout_alignment(buf, &entries) {
+        return Ok(SimpleArchiveLayout::aligned(alignment));
     }
 
-    for file in files {
-        cursor.write_all(file).unwrap();
+    Ok(SimpleArchiveLayout {
+        alignment: DEFAULT_ALIGNMENT,
+        offsets: Some(
+            entries
+                .iter()
+                .map(|&(file_offset, _)| file_offset as u32)
+                .collect(),
+        ),
+        size: Some(buf.len() as u32),
+    })
+}
 
-        let original_size = file.len() as u32;
-        let padded_size = align_up(original_size, ALIGNMENT);
-        let padding_needed = padded_size - original_size;
+fn detect_layout_alignment(buf: &[u8], entries: &[(usize, usize)]) -> Option<u32> {
+    let header_size = 4 + entries.len() * 8;
 
-        if padding_needed > 0 {
-            cursor.write_all(&vec![0; padding_needed as usize]).unwrap();
-        }
-    }
+    CANDIDATE_ALIGNMENTS.into_iter().find(|&alignment| {
+        let mut expected_off = header_size;
+        let matches = entries.iter().all(|&(file_offset, file_size)| {
+            let is_expected = file_offset == expected_off;
+            expected_off += align_up(file_size as u32, alignment) as usize;
+            is_expected
+        });
 
-    cursor.into_inner()
+        matches && expected_off == buf.len()
+    })
 }
 
-pub fn is_buf_simple_archive(buf: &[u8]) -> bool {
-    let mut cursor = Cursor::new(buf);
-    let file_count = cursor.read_u32::<LittleEndian>().unwrap_or(10000);
+pub fn encode_simple_archive(files: &[Vec<u8>]) -> Result<Vec<u8>> {
+    encode_simple_archive_aligned(files, DEFAULT_ALIGNMENT)
+}
 
-    if file_count >= 9999 {
-        return false;
+pub fn encode_simple_archive_aligned(files: &[Vec<u8>], alignment: u32) -> Result<Vec<u8>> {
+    encode_simple_archive_with_layout(files, &SimpleArchiveLayout::aligned(alignment))
+}
+
+/// Encodes `files` following `layout`. Entries keep their original offset while the data
+/// before them still fits, and reuse the data of an earlier entry they shared an offset
+/// with if they are empty or identical to it. Anything else is placed by `alignment`.
+pub fn encode_simple_archive_with_layout(
+    files: &[Vec<u8>],
+    layout: &SimpleArchiveLayout,
+) -> Result<Vec<u8>> {
+    let alignment = layout.alignment;
+    if !alignment.is_power_of_two() {
+        return Err(Error::malformed(
+            Format::SimpleArchive,
+            0,
+            format!("alignment {} is not a power of two", alignment),
+        ));
     }
 
-    let mut buf_size = 0;
+    let original_offsets = layout.offsets.as_deref().unwrap_or_default();
+    let header_size = 4 + files.len() * 8;
+    let mut data = Vec::new();
+    let mut table: Vec<(usize, usize)> = Vec::with_capacity(files.len());
+    // End of the written data, and where the next entry goes when placed by alignment
+    let mut pos = header_size;
+    let mut next_aligned = header_size;
+
+    for (i, file) in files.iter().enumerate() {
+        let original = original_offsets.get(i).copied();
+        let shared = original.and_then(|original| {
+            (0..i).find(|&j| {
+                original_offsets[j] == original && (file.is_empty() || files[j] == *file)
+            })
+        });
+        if let Some(j) = shared {
+            table.push((table[j].0, file.len()));
+            continue;
+        }
 
-    for _ in 0..file_count {
-        let file_offset = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let file_size = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        let file_offset = match original.map(|o| o as usize) {
+            Some(o) if o >= pos && o - pos < MAX_PADDING => o,
+            _ => next_aligned,
+        };
+        data.resize(file_offset - header_size, 0);
+        data.extend_from_slice(file);
+        table.push((file_offset, file.len()));
 
-        buf_size += file_size;
+        pos = file_offset + file.len();
+        next_aligned = file_offset + align_up(file.len() as u32, alignment) as usize;
+    }
 
-        if file_offset > buf.len() || file_offset + file_size > buf.len() {
-            return false;
-        }
+    let end = match layout.size.map(|s| s as usize) {
+        Some(size) if size >= pos && size - pos < MAX_PADDING => size,
+        _ => next_aligned,
+    };
+    data.resize(end - header_size, 0);
+
+    if end > u32::MAX as usize {
+        return Err(Error::TooLarge {
+            format: Format::SimpleArchive,
+            size: end,
+        });
+    }
+
+    let mut out_buf = Vec::with_capacity(end);
+    out_buf.write_u32::<LittleEndian>(files.len() as u32)?;
+    for (file_offset, file_size) in table {
+        out_buf.write_u32::<LittleEndian>(file_offset as u32)?;
+        out_buf.write_u32::<LittleEndian>(file_size as u32)?;
     }
+    out_buf.write_all(&data)?;
 
-    let header_size = cursor.position() as usize;
+    Ok(out_buf)
+}
+
+/// Confidence `is_buf_simple_archive` requires, see `simple_archive_confidence`.
+pub const DEFAULT_CONFIDENCE_THRESHOLD: f32 = 0.5;
+
+// Archives with more entries than this are assumed to be something else
+const MAX_ENTRY_COUNT: usize = 9999;
+// Largest gap between entries that is taken as padding, matching the largest candidate alignment
+const MAX_PADDING: usize = 32;
+
+pub fn is_buf_simple_archive(buf: &[u8]) -> bool {
+    simple_archive_confidence(buf) >= DEFAULT_CONFIDENCE_THRESHOLD
+}
+
+/// Scores how likely `buf` is to be a Simple Archive, from 0 (it can't be one) to 1.
+///
+/// The entry table has to fit in the buffer, entries must follow each other without
+/// overlapping the table or each other, and any gap between them must be a few zero
+/// bytes of padding. Entries repeating the previous offset (shared data) and empty
+/// entries are allowed. Layouts explained by a single alignment score highest,
+/// tables of only empty entries lowest.
+pub fn simple_archive_confidence(buf: &[u8]) -> f32 {
+    let Some(count) = buf
+        .get(0..4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    else {
+        return 0.0;
+    };
+    if count == 0 || count as usize > MAX_ENTRY_COUNT {
+        return 0.0;
+    }
+    let Ok(entries) = read_entry_table(buf) else {
+        return 0.0;
+    };
+
+    let header_size = 4 + entries.len() * 8;
+    let is_padding = |start: usize, end: usize| {
+        end - start < MAX_PADDING && buf[start..end].iter().all(|&b| b == 0)
+    };
+
+    let mut expected_off = header_size;
+    let mut previous: Option<(usize, usize)> = None;
+    for &(file_offset, file_size) in &entries {
+        let Some(file_end) = file_offset.checked_add(file_size) else {
+            return 0.0;
+        };
+        if file_end > buf.len() {
+            return 0.0;
+        }
 
-    if buf_size + header_size != buf.len() {
-        return false;
+        let is_shared = previous.is_some_and(|(prev_offset, prev_size)| {
+            file_offset == prev_offset && (file_size == prev_size || file_size == 0)
+        });
+        if !is_shared {
+            if file_offset < expected_off || !is_padding(expected_off, file_offset) {
+                return 0.0;
+            }
+            expected_off = file_end;
+        }
+        previous = Some((file_offset, file_size));
+    }
+    if !is_padding(expected_off, buf.len()) {
+        return 0.0;
     }
 
-    true
+    let mut confidence = 1.0;
+    if entries.iter().all(|&(_, file_size)| file_size == 0) {
+        confidence *= 0.2;
+    }
+    let has_gaps = detect_layout_alignment(buf, &entries).is_none();
+    if has_gaps {
+        // Zero padding, but not the one any candidate alignment produces
+        confidence *= 0.6;
+    }
+    confidence
 }
 
 #[cfg(test)]
@@ -93,7 +262,11 @@ pub mod test {
 
     use crate::simple_archive::is_buf_simple_archive;
 
-    use super::{decode_simple_archive, encode_simple_archive};
+    use super::{
+        SimpleArchiveLayout, decode_simple_archive, detect_alignment, detect_layout,
+        encode_simple_archive, encode_simple_archive_aligned, encode_simple_archive_with_layout,
+        simple_archive_confidence,
+    };
 
     #[test]
     fn simple_archive_scan() {
@@ -110,9 +283,119 @@ pub mod test {
     #[test]
     fn simple_archive_roundtrip() {
         let simple_archive = fs::read("./tests/data/em125_decrypt.pac").unwrap();
-        let files = decode_simple_archive(&simple_archive);
+        let files = decode_simple_archive(&simple_archive).unwrap();
 
-        let encoded = encode_simple_archive(&files);
+        let encoded = encode_simple_archive(&files).unwrap();
         assert!(encoded == simple_archive, "the buffers are not equal");
     }
+
+    #[test]
+    fn truncated_table_is_not_an_archive() {
+        let buf: &[u8] = &[2, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0];
+        assert!(!is_buf_simple_archive(buf));
+        assert!(decode_simple_archive(buf).is_err());
+    }
+
+    #[test]
+    fn entry_past_end_is_an_error() {
+        let buf: &[u8] = &[1, 0, 0, 0, 12, 0, 0, 0, 8, 0, 0, 0, 0xAA, 0xBB];
+        assert!(decode_simple_archive(buf).is_err());
+    }
+
+    #[test]
+    fn alignment_is_detected() {
+        let files = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8], vec![9]];
+        for alignment in [1, 4, 16] {
+            let encoded = encode_simple_archive_aligned(&files, alignment).unwrap();
+            assert_eq!(detect_alignment(&encoded).unwrap(), alignment);
+        }
+    }
+
+    #[test]
+    fn padded_archives_are_detected() {
+        let files = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8], vec![9]];
+        for alignment in [1, 4, 16] {
+            let encoded = encode_simple_archive_aligned(&files, alignment).unwrap();
+            assert_eq!(simple_archive_confidence(&encoded), 1.0);
+        }
+    }
+
+    #[test]
+    fn confidence_rejects_lookalikes() {
+        let files = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8]];
+        let encoded = encode_simple_archive_aligned(&files, 4).unwrap();
+
+        // Non-zero padding
+        let mut dirty = encoded.clone();
+        dirty[20 + 3] = 0xFF;
+        assert_eq!(simple_archive_confidence(&dirty), 0.0);
+
+        // Second entry overlapping the first
+        let mut overlapping = encoded.clone();
+        overlapping[12] = 21;
+        assert_eq!(simple_archive_confidence(&overlapping), 0.0);
+
+        // Entries out of order
+        let mut swapped = encoded.clone();
+        swapped[4..12].copy_from_slice(&encoded[12..20]);
+        swapped[12..20].copy_from_slice(&encoded[4..12]);
+        assert_eq!(simple_archive_confidence(&swapped), 0.0);
+
+        // Table running past the end, and an empty table
+        assert_eq!(simple_archive_confidence(&[200, 0, 0, 0, 1, 2]), 0.0);
+        assert_eq!(simple_archive_confidence(&[0, 0, 0, 0]), 0.0);
+
+        // Only empty entries is a weak match
+        let empty = encode_simple_archive(&[vec![], vec![]]).unwrap();
+        assert!(simple_archive_confidence(&empty) < 0.5);
+    }
+
+    #[test]
+    fn shared_entries_are_accepted() {
+        // Entry 1 repeats entry 0, entry 2 is empty at the same place
+        let buf: &[u8] = &[
+            3, 0, 0, 0, 28, 0, 0, 0, 4, 0, 0, 0, 28, 0, 0, 0, 4, 0, 0, 0, 28, 0, 0, 0, 0, 0, 0, 0,
+            1, 2, 3, 4,
+        ];
+        assert!(is_buf_simple_archive(buf));
+        assert_eq!(decode_simple_archive(buf).unwrap()[1], vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn irregular_layouts_are_reproduced() {
+        // Entry 1 shares entry 0's data, entry 2 is empty, entry 3 is followed by odd padding
+        let buf: &[u8] = &[
+            4, 0, 0, 0, 36, 0, 0, 0, 3, 0, 0, 0, 36, 0, 0, 0, 3, 0, 0, 0, 41, 0, 0, 0, 0, 0, 0, 0,
+            41, 0, 0, 0, 2, 0, 0, 0, 1, 2, 3, 0, 0, 4, 5, 0, 0, 0,
+        ];
+        assert!(is_buf_simple_archive(buf));
+        let files = decode_simple_archive(buf).unwrap();
+        assert_eq!(files[1], vec![1, 2, 3]);
+        assert!(files[2].is_empty());
+
+        let layout = detect_layout(buf).unwrap();
+        assert_eq!(layout.offsets, Some(vec![36, 36, 41, 41]));
+        assert_eq!(
+            encode_simple_archive_with_layout(&files, &layout).unwrap(),
+            buf
+        );
+
+        // Edited entries fall back to aligned placement and still make a valid archive
+        let mut edited = files.clone();
+        edited[1] = vec![7; 9];
+        let encoded = encode_simple_archive_with_layout(&edited, &layout).unwrap();
+        assert!(is_buf_simple_archive(&encoded));
+        assert_eq!(decode_simple_archive(&encoded).unwrap(), edited);
+    }
+
+    #[test]
+    fn aligned_layouts_are_reproduced() {
+        let files = vec![vec![1, 2, 3], vec![], vec![4, 5, 6, 7, 8]];
+        for alignment in [1, 4, 16] {
+            let encoded = encode_simple_archive_aligned(&files, alignment).unwrap();
+            let layout = detect_layout(&encoded).unwrap();
+            assert_eq!(layout, SimpleArchiveLayout::aligned(alignment));
+            assert_eq!(decode_simple_archive(&encoded).unwrap(), files);
+        }
+    }
 }
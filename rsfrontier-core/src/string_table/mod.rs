@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{
+    error::{Error, Result},
+    text::{TextEntry, decode_sjis, encode_sjis},
+};
+
+const MAX_STRING_LEN: usize = 2048;
+
+/// Controls which values of a data file (mhfdat, mhfpac, mhfinf...) are taken as string pointers.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Where to look for pointers, the whole buffer when `None`.
+    pub pointer_range: Option<Range<usize>>,
+    /// Pointers are only read at offsets that are a multiple of this.
+    pub alignment: usize,
+    /// Strings shorter than this many bytes are ignored, short strings are mostly false positives.
+    pub min_length: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            pointer_range: None,
+            alignment: 4,
+            min_length: 2,
+        }
+    }
+}
+
+/// A string found in a data file, with the offsets of every pointer to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PointedString {
+    pub offset: u32,
+    pub pointers: Vec<u32>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StringTable {
+    /// Sorted by offset.
+    pub strings: Vec<PointedString>,
+}
+
+impl StringTable {
+    /// One entry per string, the id is the string's offset in the original buffer.
+    pub fn to_entries(&self) -> Vec<TextEntry> {
+        self.strings
+            .iter()
+            .map(|s| TextEntry {
+                id: format_id(s.offset),
+                text: s.text.clone(),
+            })
+            .collect()
+    }
+}
+
+fn format_id(offset: u32) -> String {
+    format!("0x{:08X}", offset)
+}
+
+fn parse_id(id: &str) -> Option<u32> {
+    let hex = id.strip_prefix("0x").or_else(|| id.strip_prefix("0X"))?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Decodes the null terminated string at `offset` if it looks like game text.
+fn read_string_at(buf: &[u8], offset: usize, min_length: usize) -> Option<String> {
+    let end = buf.len().min(offset + MAX_STRING_LEN + 1);
+    let len = buf[offset..end].iter().position(|&b| b == 0)?;
+    if len < min_length.max(1) {
+        return None;
+    }
+
+    let text = decode_sjis(&buf[offset..offset + len])?;
+    if text.chars().any(|c| c.is_control() && c != '\n') {
+        return None;
+    }
+    Some(text)
+}
+
+/// Finds every value in `buf` that points at the start of a Shift-JIS string.
+/// This is a heuristic, narrow `options.pointer_range` to the file's pointer tables
+/// to avoid false positives.
+pub fn scan_strings(buf: &[u8], options: &ScanOptions) -> StringTable {
+    let range = options.pointer_range.clone().unwrap_or(0..buf.len());
+    let alignment = options.alignment.max(1);
+    let start = range.start.next_multiple_of(alignment);
+    let end = range.end.min(buf.len());
+
+    // None marks offsets already rejected, so each candidate is only decoded once
+    let mut found: BTreeMap<u32, Option<PointedString>> = BTreeMap::new();
+    for pointer_offset in (start..end.saturating_sub(3)).step_by(alignment) {
+        let value = u32::from_le_bytes(
+            buf[pointer_offset..pointer_offset + 4]
+                .try_into()
+                .expect("Slice with incorrect length"),
+        );
+        // Strings are packed back to back, pointers into the middle of one aren't string pointers
+        let value_offset = value as usize;
+        if value_offset == 0 || value_offset >= buf.len() || buf[value_offset - 1] != 0 {
+            continue;
+        }
+
+        let entry = found.entry(value).or_insert_with(|| {
+            read_string_at(buf, value_offset, options.min_length).map(|text| PointedString {
+                offset: value,
+                pointers: Vec::new(),
+                text,
+            })
+        });
+        if let Some(string) = entry {
+            string.pointers.push(pointer_offset as u32);
+        }
+    }
+
+    StringTable {
+        strings: found.into_values().flatten().collect(),
+    }
+}
+
+/// Writes the edited `entries` back into `buf`. Changed strings are appended to the end of the
+/// buffer and every pointer to the original string is moved to the new copy, so longer
+/// translations never overwrite neighbouring data. Ids refer to `table`, which must have been
+/// scanned from `buf`.
+pub fn reinsert_strings(buf: &[u8], table: &StringTable, entries: &[TextEntry]) -> Result<Vec<u8>> {
+    let by_offset: BTreeMap<u32, &PointedString> =
+        table.strings.iter().map(|s| (s.offset, s)).collect();
+
+    let mut out_buf = buf.to_vec();
+    // Identical translations share one copy
+    let mut appended: BTreeMap<Vec<u8>, u32> = BTreeMap::new();
+
+    for entry in entries {
+        let string = parse_id(&entry.id)
+            .and_then(|offset| by_offset.get(&offset))
+            .ok_or_else(|| Error::Text(format!("id '{}' is not a scanned string", entry.id)))?;
+        if string.text == entry.text {
+            continue;
+        }
+
+        let bytes = encode_sjis(&entry.text).ok_or_else(|| {
+            Error::Text(format!("string {} can't be encoded as Shift-JIS", entry.id))
+        })?;
+        if bytes.contains(&0) {
+            return Err(Error::Text(format!(
+                "string {} contains a null character",
+                entry.id
+            )));
+        }
+
+        let new_offset = match appended.get(&bytes) {
+            Some(&offset) => offset,
+            None => {
+                let offset: u32 = out_buf.len().try_into().map_err(|_| {
+                    Error::Text("the buffer is too large for 32 bit pointers".to_string())
+                })?;
+                out_buf.extend_from_slice(&bytes);
+                out_buf.push(0);
+                appended.insert(bytes, offset);
+                offset
+            }
+        };
+
+        for &pointer in &string.pointers {
+            // Only the original data holds pointers, not the strings appended after it
+            let start = pointer as usize;
+            let slot = out_buf[..buf.len()]
+                .get_mut(start..start + 4)
+                .ok_or_else(|| {
+                    Error::Text(format!(
+                        "pointer at 0x{:08X} to string {} is outside the buffer, the table \
+                         wasn't scanned from it",
+                        pointer, entry.id
+                    ))
+                })?;
+            slot.copy_from_slice(&new_offset.to_le_bytes());
+        }
+    }
+
+    Ok(out_buf)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::Error,
+        text::{TextEntry, encode_sjis},
+    };
+
+    use super::{ScanOptions, reinsert_strings, scan_strings};
+
+    /// Two pointers to a name, one to a description, then the strings.
+    fn sample() -> Vec<u8> {
+        let mut buf = vec![0; 16];
+        let name = buf.len() as u32;
+        buf.extend_from_slice(&encode_sjis("大剣").unwrap());
+        buf.push(0);
+        let description = buf.len() as u32;
+        buf.extend_from_slice(&encode_sjis("重い剣").unwrap());
+        buf.push(0);
+
+        buf[0..4].copy_from_slice(&name.to_le_bytes());
+        buf[4..8].copy_from_slice(&description.to_le_bytes());
+        buf[8..12].copy_from_slice(&name.to_le_bytes());
+        // 1 points into the middle of nothing and must be ignored
+        buf[12..16].copy_from_slice(&1u32.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn strings_are_found() {
+        let buf = sample();
+        let table = scan_strings(&buf, &ScanOptions::default());
+
+        assert_eq!(table.strings.len(), 2);
+        assert_eq!(table.strings[0].text, "大剣");
+        assert_eq!(table.strings[0].pointers, vec![0, 8]);
+        assert_eq!(table.strings[1].text, "重い剣");
+        assert_eq!(table.strings[1].pointers, vec![4]);
+
+        let options = ScanOptions {
+            pointer_range: Some(4..8),
+            ..Default::default()
+        };
+        assert_eq!(scan_strings(&buf, &options).strings.len(), 1);
+    }
+
+    #[test]
+    fn strings_are_relocated() {
+        let buf = sample();
+        let table = scan_strings(&buf, &ScanOptions::default());
+        let mut entries = table.to_entries();
+        entries[0].text = "Great Sword".to_string();
+
+        let patched = reinsert_strings(&buf, &table, &entries).unwrap();
+        assert_eq!(patched[16..buf.len()], buf[16..]);
+        assert_eq!(patched[4..8], buf[4..8]);
+
+        let patched_table = scan_strings(&patched, &ScanOptions::default());
+        let texts: Vec<_> = patched_table.strings.iter().map(|s| &s.text).collect();
+        assert_eq!(texts, vec!["重い剣", "Great Sword"]);
+        assert_eq!(patched_table.strings[1].pointers, vec![0, 8]);
+
+        let unknown = TextEntry {
+            id: "0x00000003".to_string(),
+            text: String::new(),
+        };
+        assert!(reinsert_strings(&buf, &table, &[unknown]).is_err());
+    }
+
+    #[test]
+    fn mismatched_table_is_an_error() {
+        let buf = sample();
+        let table = scan_strings(&buf, &ScanOptions::default());
+        let mut entries = table.to_entries();
+        entries[0].text = "Great Sword".to_string();
+
+        // A shorter buffer doesn't have the pointers the table lists
+        assert!(matches!(
+            reinsert_strings(&buf[..6], &table, &entries),
+            Err(Error::Text(_))
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use encoding_rs::SHIFT_JIS;
+
+use crate::error::{Error, Result};
+
+const ID_COLUMN: &str = "id";
+const TEXT_COLUMN: &str = "text";
+
+/// Spreadsheet formats strings are exported to for translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl TableFormat {
+    /// Picks the format from a `.csv`/`.tsv` extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match ext.as_str() {
+            "csv" => Some(TableFormat::Csv),
+            "tsv" => Some(TableFormat::Tsv),
+            _ => None,
+        }
+    }
+
+    fn delimiter(self) -> u8 {
+        match self {
+            TableFormat::Csv => b',',
+            TableFormat::Tsv => b'\t',
+        }
+    }
+}
+
+/// One row of a text table, `id` identifies where the string goes back on import.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEntry {
+    pub id: String,
+    pub text: String,
+}
+
+/// Decodes a Shift-JIS string, `None` if it contains invalid sequences.
+pub fn decode_sjis(bytes: &[u8]) -> Option<String> {
+    SHIFT_JIS
+        .decode_without_bom_handling_and_without_replacement(bytes)
+        .map(|text| text.into_owned())
+}
+
+/// Encodes `text` as Shift-JIS, `None` if it has characters Shift-JIS can't represent.
+pub fn encode_sjis(text: &str) -> Option<Vec<u8>> {
+    let (bytes, _, had_unmappable) = SHIFT_JIS.encode(text);
+    if had_unmappable {
+        return None;
+    }
+    Some(bytes.into_owned())
+}
+
+/// Writes `entries` as a table with an `id` and a `text` column.
+pub fn write_table(entries: &[TextEntry], format: TableFormat) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(format.delimiter())
+        .from_writer(Vec::new());
+
+    writer
+        .write_record([ID_COLUMN, TEXT_COLUMN])
+        .map_err(|e| Error::Text(e.to_string()))?;
+    for entry in entries {
+        writer
+            .write_record([&entry.id, &entry.text])
+            .map_err(|e| Error::Text(e.to_string()))?;
+    }
+
+    let bytes = writer
+        .into_inner()
+        .map_err(|e| Error::Text(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|e| Error::Text(e.to_string()))
+}
+
+/// Reads a table written by `write_table`. Columns are found by their header name,
+/// so extra columns (notes, original text...) added by translators are ignored.
+pub fn read_table(table: &str, format: TableFormat) -> Result<Vec<TextEntry>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(format.delimiter())
+        .flexible(true)
+        .from_reader(table.as_bytes());
+
+    let headers = reader
+        .headers()
+        .map_err(|e| Error::Text(e.to_string()))?
+        .clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| Error::Text(format!("missing '{}' column", name)))
+    };
+    let id_column = column(ID_COLUMN)?;
+    let text_column = column(TEXT_COLUMN)?;
+
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| Error::Text(e.to_string()))?;
+        let line = record.position().map_or(0, |p| p.line());
+        let field = |column: usize| {
+            record
+                .get(column)
+                .ok_or_else(|| Error::Text(format!("line {}: missing column", line)))
+        };
+        entries.push(TextEntry {
+            id: field(id_column)?.trim().to_string(),
+            text: field(text_column)?.to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TableFormat, TextEntry, decode_sjis, encode_sjis, read_table, write_table};
+
+    #[test]
+    fn table_roundtrip() {
+        let entries = vec![
+            TextEntry {
+                id: "0".to_string(),
+                text: "リオレウス".to_string(),
+            },
+            TextEntry {
+                id: "1".to_string(),
+                text: "two\nlines, \"quoted\"\tand tabbed".to_string(),
+            },
+            TextEntry {
+                id: "2".to_string(),
+                text: String::new(),
+            },
+        ];
+
+        for format in [TableFormat::Csv, TableFormat::Tsv] {
+            let table = write_table(&entries, format).unwrap();
+            assert_eq!(read_table(&table, format).unwrap(), entries);
+        }
+    }
+
+    #[test]
+    fn extra_columns_are_ignored() {
+        let table = "note,text,id\nhello,translated,7\n";
+        let entries = read_table(table, TableFormat::Csv).unwrap();
+        assert_eq!(
+            entries,
+            vec![TextEntry {
+                id: "7".to_string(),
+                text: "translated".to_string()
+            }]
+        );
+        assert!(read_table("id,note\n1,a\n", TableFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn sjis_roundtrip() {
+        let bytes = encode_sjis("大剣 Great Sword").unwrap();
+        assert_eq!(decode_sjis(&bytes).unwrap(), "大剣 Great Sword");
+        assert!(encode_sjis("😀").is_none());
+        assert!(decode_sjis(&[0x81]).is_none());
+    }
+}
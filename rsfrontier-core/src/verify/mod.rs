@@ -0,0 +1,414 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    PackOptions, UnpackOptions, apply_layers,
+    entry::{Archive, StopAt, join_entry, strip_layers},
+    error::{Error, Result},
+    pack_archive_folder, repack_node, run_with_jobs, unpack_on_current_pool,
+};
+
+/// Outcome of rebuilding every node of one or more files, in tree order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    pub nodes: Vec<VerifiedNode>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, status: VerifyStatus) -> usize {
+        self.nodes.iter().filter(|n| n.status == status).count()
+    }
+
+    /// Whether every node came out byte-identical.
+    pub fn is_identical(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|n| n.status == VerifyStatus::Identical)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifiedNode {
+    pub file: String,
+    /// Entry path of the node inside `file`, as taken by `extract_entry`, empty for the file.
+    pub entry: String,
+    /// Size of the node as stored, layers included.
+    pub size: usize,
+    pub status: VerifyStatus,
+    /// Why the file couldn't be unpacked, for `VerifyStatus::Failed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    /// The rebuilt node is byte for byte the original.
+    Identical,
+    /// The rebuilt node has other bytes but unpacks to the same data, usually because JPK
+    /// layers were compressed differently.
+    SameData,
+    /// The rebuilt node unpacks to other data or another layout.
+    Differs,
+    /// The file couldn't be unpacked.
+    Failed,
+}
+
+/// Unpacks `buf`, rebuilds every node from the parameters it was read with (ECD/EXF key,
+/// JPK type, Simple Archive layout, MHA IDs) and compares it to the original. The whole
+/// buffer is also unpacked to a temporary folder and rebuilt from the files on disk, both
+/// with `repack_from_manifest` and, for archives, from the folder's markers like `pack`.
+/// `prefix_path` is the path `unpack_buffer_with_options` would be given, it names the
+/// file in the report and is used to match `unpack_options.simple_archive_overrides`.
+pub fn verify_buffer(
+    prefix_path: &str,
+    buf: &[u8],
+    unpack_options: &UnpackOptions,
+    pack_options: &PackOptions,
+) -> Result<VerifyReport> {
+    let path = PathBuf::from(prefix_path);
+    let verifier = Verifier {
+        file: prefix_path,
+        root: path.parent().unwrap_or(Path::new("")),
+        unpack_options,
+        pack_options,
+    };
+    run_with_jobs(unpack_options.jobs, || {
+        Ok(VerifyReport {
+            nodes: verifier.verify_root(buf, &path)?,
+        })
+    })
+}
+
+/// Runs `verify_buffer` on a file, or on every file under a directory. Files that can't be
+/// unpacked are reported as `VerifyStatus::Failed` instead of stopping the others.
+pub fn verify_path(
+    path: &Path,
+    unpack_options: &UnpackOptions,
+    pack_options: &PackOptions,
+) -> Result<VerifyReport> {
+    let files = if path.is_dir() {
+        collect_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    run_with_jobs(unpack_options.jobs, || {
+        let nodes = files
+            .par_iter()
+            .map(|file| verify_file(file, unpack_options, pack_options))
+            .collect::<Vec<_>>();
+        Ok(VerifyReport {
+            nodes: nodes.into_iter().flatten().collect(),
+        })
+    })
+}
+
+fn verify_file(
+    file: &Path,
+    unpack_options: &UnpackOptions,
+    pack_options: &PackOptions,
+) -> Vec<VerifiedNode> {
+    let name = file.to_string_lossy();
+    let buf = match fs::read(file) {
+        Ok(buf) => buf,
+        Err(e) => return vec![failed_node(&name, 0, Error::from(e).in_layer(file))],
+    };
+
+    let prefix_path = file.with_extension("");
+    let verifier = Verifier {
+        file: &name,
+        root: prefix_path.parent().unwrap_or(Path::new("")),
+        unpack_options,
+        pack_options,
+    };
+    match verifier.verify_root(&buf, &prefix_path) {
+        Ok(nodes) => nodes,
+        Err(e) => vec![failed_node(&name, buf.len(), e)],
+    }
+}
+
+fn failed_node(file: &str, size: usize, error: Error) -> VerifiedNode {
+    VerifiedNode {
+        file: file.to_string(),
+        entry: String::new(),
+        size,
+        status: VerifyStatus::Failed,
+        error: Some(error.to_string()),
+    }
+}
+
+/// Lists every file under `dir`, recursively and in a stable order.
+fn collect_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+
+    let mut files = Vec::new();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(collect_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// A folder under the system temporary directory, deleted on drop.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "rsfrontier-verify-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).map_err(|e| Error::from(e).in_layer(&path))?;
+        Ok(ScratchDir(path))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Verifier<'a> {
+    file: &'a str,
+    root: &'a Path,
+    unpack_options: &'a UnpackOptions,
+    pack_options: &'a PackOptions,
+}
+
+impl Verifier<'_> {
+    /// Verifies `buf` in memory node by node, then on disk as a whole.
+    fn verify_root(&self, buf: &[u8], path: &Path) -> Result<Vec<VerifiedNode>> {
+        let (_, mut nodes) = self.verify_node(buf, path, "")?;
+        let on_disk = self.verify_on_disk(buf, path)?;
+        nodes[0].status = nodes[0].status.max(on_disk);
+        Ok(nodes)
+    }
+
+    /// Unpacks `buf` to a temporary folder like `unpack --manifest` and rebuilds it from there.
+    fn verify_on_disk(&self, buf: &[u8], path: &Path) -> Result<VerifyStatus> {
+        let scratch = ScratchDir::new()?;
+        let prefix_path = scratch.0.join(path.file_name().unwrap_or("file".as_ref()));
+        let output = unpack_on_current_pool(&prefix_path, buf, self.unpack_options)?;
+        for (file_path, data) in &output.files {
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent).map_err(|e| Error::from(e).in_layer(parent))?;
+            }
+            fs::write(file_path, data).map_err(|e| Error::from(e).in_layer(file_path))?;
+        }
+
+        let mut rebuilt = vec![repack_node(
+            &output.manifest.root,
+            &scratch.0,
+            self.pack_options,
+        )?];
+        if prefix_path.is_dir() {
+            rebuilt.push(pack_archive_folder(&prefix_path, self.pack_options)?);
+        }
+
+        let mut status = VerifyStatus::Identical;
+        for rebuilt in rebuilt {
+            if rebuilt == buf {
+                continue;
+            }
+            let same_files = unpack_on_current_pool(&prefix_path, &rebuilt, self.unpack_options)
+                .is_ok_and(|rebuilt_output| rebuilt_output.files == output.files);
+            status = status.max(if same_files {
+                VerifyStatus::SameData
+            } else {
+                VerifyStatus::Differs
+            });
+        }
+        Ok(status)
+    }
+
+    /// Returns `buf` rebuilt, with the result of its node followed by those of its entries.
+    /// `path` is where `unpack` would write the node, `entry` its entry path.
+    fn verify_node(
+        &self,
+        buf: &[u8],
+        path: &Path,
+        entry: &str,
+    ) -> Result<(Vec<u8>, Vec<VerifiedNode>)> {
+        let (layers, data) = strip_layers(buf.to_vec(), path, StopAt::Data, self.unpack_options)?;
+        let archive = Archive::detect(&data, path, self.root, self.unpack_options)
+            .map_err(|e| e.in_layer(path))?;
+
+        let mut nodes = Vec::new();
+        // Whether the entries and their layout decode to the original data
+        let (rebuilt_data, content_matches) = match archive {
+            None => (data.clone(), true),
+            Some(mut archive) => {
+                let layout_matches = archive.encode().map_err(|e| e.in_layer(path))? == data;
+                let children = archive
+                    .entry_names()
+                    .into_par_iter()
+                    .enumerate()
+                    .map(|(i, (folder, name))| {
+                        self.verify_node(
+                            archive.entry(i),
+                            &path.join(folder),
+                            &join_entry(entry, &name),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let mut children_match = true;
+                for (i, (rebuilt, child_nodes)) in children.into_iter().enumerate() {
+                    children_match &= child_nodes[0].status != VerifyStatus::Differs;
+                    *archive.entry_mut(i) = rebuilt;
+                    nodes.extend(child_nodes);
+                }
+                let rebuilt_data = archive.encode().map_err(|e| e.in_layer(path))?;
+                (rebuilt_data, layout_matches && children_match)
+            }
+        };
+
+        let rebuilt = apply_layers(rebuilt_data.clone(), &layers, self.pack_options)
+            .map_err(|e| e.in_layer(path))?;
+        let status = if rebuilt == buf {
+            VerifyStatus::Identical
+        } else {
+            // The new layers must decode back to what they were applied to
+            let (rebuilt_layers, decoded) =
+                strip_layers(rebuilt.clone(), path, StopAt::Data, self.unpack_options)?;
+            if content_matches && rebuilt_layers == layers && decoded == rebuilt_data {
+                VerifyStatus::SameData
+            } else {
+                VerifyStatus::Differs
+            }
+        };
+
+        nodes.insert(
+            0,
+            VerifiedNode {
+                file: self.file.to_string(),
+                entry: entry.to_string(),
+                size: buf.len(),
+                status,
+                error: None,
+            },
+        );
+        Ok((rebuilt, nodes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{
+        PackOptions, UnpackOptions, ecd,
+        jpk::{CompressionLevel, create_jpk_with},
+        mha::{MhaEntry, encode_mha_archive},
+        simple_archive::encode_simple_archive_aligned,
+    };
+
+    use super::{VerifyStatus, verify_buffer};
+
+    #[test]
+    fn nodes_are_classified() {
+        let uncomp = fs::read("tests/data/quest_ex_0_uncomp.bin").unwrap();
+        let compressed = create_jpk_with(&uncomp, 3, CompressionLevel::Fast).unwrap();
+        let mha =
+            encode_mha_archive(&[MhaEntry::new("a.bin".to_string(), 3, vec![7; 8])], 3, 8).unwrap();
+        let archive = encode_simple_archive_aligned(&[compressed, mha], 1).unwrap();
+        let buf = ecd::encrypt_ecd(&archive).unwrap();
+
+        let unpack_options = UnpackOptions::default();
+        let fast = PackOptions {
+            compression: CompressionLevel::Fast,
+            ..Default::default()
+        };
+        let report = verify_buffer("file", &buf, &unpack_options, &fast).unwrap();
+        let entries: Vec<_> = report.nodes.iter().map(|n| n.entry.as_str()).collect();
+        assert_eq!(entries, vec!["", "0000", "0001", "0001/a.bin"]);
+        assert!(report.is_identical());
+
+        let max = PackOptions {
+            compression: CompressionLevel::Max,
+            ..Default::default()
+        };
+        let report = verify_buffer("file", &buf, &unpack_options, &max).unwrap();
+        let statuses: Vec<_> = report.nodes.iter().map(|n| n.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                VerifyStatus::SameData,
+                VerifyStatus::SameData,
+                VerifyStatus::Identical,
+                VerifyStatus::Identical,
+            ]
+        );
+        assert!(
+            report
+                .to_json()
+                .unwrap()
+                .contains("\"status\": \"same_data\"")
+        );
+    }
+
+    #[test]
+    fn unpacked_files_are_repacked() {
+        // Both entries have the stem 'a', they must get their own file on disk
+        let archive = encode_mha_archive(
+            &[
+                MhaEntry::new("a.bin".to_string(), 3, vec![1; 8]),
+                MhaEntry::new("a.dat".to_string(), 4, vec![2; 8]),
+            ],
+            3,
+            8,
+        )
+        .unwrap();
+
+        let report = verify_buffer(
+            "file",
+            &archive,
+            &UnpackOptions::default(),
+            &PackOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.nodes.len(), 3);
+        assert!(report.is_identical());
+    }
+
+    #[test]
+    fn lost_padding_is_reported() {
+        // Entries aligned to 16 bytes, with garbage in the padding that encoding can't keep
+        let mut archive = encode_simple_archive_aligned(&[vec![1; 3], vec![2; 5]], 16).unwrap();
+        let padding = archive.iter().rposition(|&b| b == 1).unwrap() + 1;
+        archive[padding] = 0xAA;
+
+        // Too small to be detected on its own
+        let unpack_options = UnpackOptions {
+            simple_archive_overrides: [("file".to_string(), true)].into(),
+            ..Default::default()
+        };
+        let report =
+            verify_buffer("file", &archive, &unpack_options, &PackOptions::default()).unwrap();
+        assert_eq!(report.nodes[0].status, VerifyStatus::Differs);
+        assert_eq!(report.count(VerifyStatus::Identical), 2);
+    }
+}